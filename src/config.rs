@@ -1,3 +1,5 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
 use anyhow::Context;
 
 use getset::Getters;
@@ -12,8 +14,11 @@ use tokio::{fs::File, io::AsyncReadExt, sync::OnceCell};
 #[getset(get = "pub")]
 pub struct ContextConfiguration {
     context: String,
+    #[serde(default)]
+    response_headers: BTreeMap<String, String>,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub enum ServerType {
     TCP,
@@ -35,6 +40,175 @@ pub struct ServerConfiguration {
     fastcgi_connection_configuration: FastCGIConnectionConfiguration,
 }
 
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLimitAction {
+    #[default]
+    Kill,
+    Discard,
+}
+
+// What startup does with a command whose definition fails validation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidCommandAction {
+    #[default]
+    Fail,
+    Disable,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CommandParameterType {
+    Integer { min: Option<i64>, max: Option<i64> },
+    Enum { values: Vec<String> },
+    String { pattern: String },
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct CommandParameter {
+    name: String,
+    #[serde(flatten)]
+    parameter_type: CommandParameterType,
+    // Index into args at which the value is inserted.
+    position: usize,
+    #[serde(default)]
+    required: bool,
+    default: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandStdin {
+    #[default]
+    Null,
+    Inherit,
+    File(PathBuf),
+    // The HTTP request body, up to max_stdin_bytes.
+    RequestBody,
+}
+
+// A further command in a pipeline, reading the previous stage's stdout.
+#[derive(Debug, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct PipelineStage {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+// Replaces matches of pattern in stdout and stderr.  The replacement may refer to capture
+// groups as $1 or $name.
+#[derive(Debug, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct RedactionRule {
+    pattern: String,
+    #[serde(default = "default_redaction_replacement")]
+    replacement: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    // stdout is one JSON value.
+    Json,
+    // stdout is one JSON value per line, blank lines are skipped.
+    #[serde(alias = "json-lines")]
+    JsonLines,
+    // One row per line, split on whitespace or delimiter into columns named by the header
+    // line or by columns.  The last column takes the rest of the line.
+    Columns {
+        #[serde(default)]
+        skip_lines: usize,
+        #[serde(default = "default_columns_header")]
+        header: bool,
+        // The last header name contains spaces, such as "Mounted on" from df.
+        #[serde(default)]
+        last_header_has_spaces: bool,
+        #[serde(default)]
+        columns: Vec<String>,
+        #[serde(default)]
+        delimiter: Option<String>,
+    },
+    // One row per line matching pattern, keyed by its named capture groups.
+    Regex {
+        pattern: String,
+    },
+    // One row per block of key/value lines, blocks are separated by blank lines.
+    KeyValue {
+        #[serde(default = "default_key_value_separator")]
+        separator: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamFormat {
+    // text/plain lines, then a summary line.
+    Text,
+    // text/event-stream stdout and stderr events, then a summary event.
+    EventStream,
+}
+
+// Runs a command in the background, either every interval or at the times of a cron
+// expression with a seconds field, for example "0 */5 * * * *".
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandSchedule {
+    Interval(#[serde(with = "humantime_serde")] std::time::Duration),
+    Cron(String),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoNiceClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct IoNice {
+    class: IoNiceClass,
+    level: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct ResourceLimits {
+    nice: Option<i32>,
+    ionice: Option<IoNice>,
+    cpu_time_seconds: Option<u64>,
+    address_space_bytes: Option<u64>,
+    open_files: Option<u64>,
+    processes: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct SandboxConfiguration {
+    #[serde(default)]
+    no_new_privs: bool,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    #[serde(default)]
+    unshare_user: bool,
+    #[serde(default)]
+    unshare_network: bool,
+    // A new PID namespace applies to processes started by the command, not the command itself.
+    #[serde(default)]
+    unshare_pid: bool,
+    #[serde(default)]
+    unshare_mount: bool,
+    // When non-empty, filesystem access is limited to reading and executing beneath these paths.
+    #[serde(default)]
+    landlock_read_only_paths: Vec<PathBuf>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Getters)]
 #[getset(get = "pub")]
 pub struct CommandInfo {
@@ -43,6 +217,79 @@ pub struct CommandInfo {
     command: String,
     #[serde(default)]
     args: Vec<String>,
+    #[serde(default)]
+    parameters: Vec<CommandParameter>,
+    // Stages after the first, which is command and args.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pipeline: Vec<PipelineStage>,
+    #[serde(default, skip_serializing)]
+    response_headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing)]
+    failure_status_code: Option<u16>,
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<std::time::Duration>,
+    // Results are cached per argument list for this long.
+    #[serde(default, with = "humantime_serde")]
+    cache_ttl: Option<std::time::Duration>,
+    // Bound on cached argument lists, the oldest result is dropped to store a new one.
+    #[serde(default = "default_cache_max_entries", skip_serializing)]
+    cache_max_entries: usize,
+    // Concurrent requests with the same arguments join one run.
+    #[serde(default = "default_single_flight", skip_serializing)]
+    single_flight: bool,
+    #[serde(default)]
+    schedule: Option<CommandSchedule>,
+    // Parse stdout and include it in JSON responses as structured data.
+    #[serde(default)]
+    output_format: OutputFormat,
+    // Set false to leave stdout out of responses when output_format parsed it.
+    #[serde(default = "default_include_stdout", skip_serializing)]
+    include_stdout: bool,
+    // Applied after the global redactions.
+    #[serde(default, skip_serializing)]
+    redactions: Vec<RedactionRule>,
+    // Stream output as it is produced by default, the stream query parameter overrides it.
+    #[serde(default, skip_serializing)]
+    stream: Option<StreamFormat>,
+    // Overrides the global history_size for this command.
+    #[serde(default, skip_serializing)]
+    history_size: Option<usize>,
+    // Also keep stdout and stderr in the history, subject to max_output_bytes.
+    #[serde(default, skip_serializing)]
+    history_include_output: bool,
+    #[serde(default, skip_serializing)]
+    max_output_bytes: Option<usize>,
+    #[serde(default, skip_serializing)]
+    output_limit_action: OutputLimitAction,
+    // A null value removes the variable from the environment.
+    #[serde(default, skip_serializing)]
+    env: BTreeMap<String, Option<String>>,
+    #[serde(default, skip_serializing)]
+    env_clear: bool,
+    #[serde(default, skip_serializing)]
+    working_directory: Option<PathBuf>,
+    #[serde(default, skip_serializing)]
+    stdin: CommandStdin,
+    #[serde(default, skip_serializing)]
+    max_stdin_bytes: Option<usize>,
+    #[serde(default, skip_serializing)]
+    resource_limits: ResourceLimits,
+    #[serde(default, skip_serializing)]
+    sandbox: SandboxConfiguration,
+    #[serde(default, skip_serializing)]
+    max_concurrent: Option<usize>,
+    #[serde(default, skip_serializing, with = "humantime_serde")]
+    semaphore_acquire_timeout: Option<std::time::Duration>,
+    #[serde(default, skip_serializing)]
+    semaphore_group: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct SemaphoreGroupConfiguration {
+    max_concurrent_commands: usize,
+    #[serde(default, with = "humantime_serde")]
+    semaphore_acquire_timeout: Option<std::time::Duration>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Getters)]
@@ -51,9 +298,85 @@ pub struct CommandConfiguration {
     max_concurrent_commands: usize,
     #[serde(with = "humantime_serde")]
     semaphore_acquire_timeout: std::time::Duration,
+    // Upper bound for the wait query parameter clients use to wait longer for a semaphore.
+    #[serde(default, with = "humantime_serde")]
+    max_semaphore_acquire_timeout: Option<std::time::Duration>,
+    #[serde(default = "default_command_timeout", with = "humantime_serde")]
+    command_timeout: std::time::Duration,
+    #[serde(default = "default_max_output_bytes")]
+    max_output_bytes: usize,
+    // Largest request body passed to commands with request_body stdin.
+    #[serde(default = "default_max_stdin_bytes")]
+    max_stdin_bytes: usize,
+    // Number of recent runs kept per command for commands/{id}/history.
+    #[serde(default = "default_history_size")]
+    history_size: usize,
+    // Bound on running jobs plus finished jobs still retained, across all commands.
+    #[serde(default = "default_max_jobs")]
+    max_jobs: usize,
+    #[serde(default = "default_job_retention", with = "humantime_serde")]
+    job_retention: std::time::Duration,
+    // Applied to the output of every command.
+    #[serde(default)]
+    redactions: Vec<RedactionRule>,
+    #[serde(default)]
+    semaphore_groups: BTreeMap<String, SemaphoreGroupConfiguration>,
+    #[serde(default)]
+    invalid_commands: InvalidCommandAction,
+    // Named lists of command ids run together by dashboard/{name}.
+    #[serde(default)]
+    dashboards: BTreeMap<String, Vec<String>>,
     commands: Vec<CommandInfo>,
 }
 
+fn default_single_flight() -> bool {
+    true
+}
+
+fn default_cache_max_entries() -> usize {
+    1000
+}
+
+fn default_columns_header() -> bool {
+    true
+}
+
+fn default_key_value_separator() -> String {
+    "=".to_owned()
+}
+
+fn default_redaction_replacement() -> String {
+    "[REDACTED]".to_owned()
+}
+
+fn default_include_stdout() -> bool {
+    true
+}
+
+fn default_command_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(60)
+}
+
+fn default_max_output_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_max_stdin_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_history_size() -> usize {
+    20
+}
+
+fn default_max_jobs() -> usize {
+    100
+}
+
+fn default_job_retention() -> std::time::Duration {
+    std::time::Duration::from_secs(10 * 60)
+}
+
 #[derive(Debug, Deserialize, Serialize, Getters)]
 #[getset(get = "pub")]
 pub struct Configuration {
@@ -92,3 +415,9 @@ pub async fn read_configuration(config_file: String) -> anyhow::Result<()> {
 pub fn instance() -> &'static Configuration {
     CONFIGURATION_INSTANCE.get().unwrap()
 }
+
+// Configuration values for tests, leaked to be 'static like the loaded configuration.
+#[cfg(test)]
+pub fn leak_test_fixture<T: serde::de::DeserializeOwned>(json: serde_json::Value) -> &'static T {
+    Box::leak(Box::new(serde_json::from_value(json).unwrap()))
+}
@@ -1,8 +1,11 @@
 mod commands;
+mod middleware;
 mod request_info;
 mod route;
 mod utils;
 
+use std::sync::Arc;
+
 use async_trait::async_trait;
 
 use crate::{request::FastCGIRequest, response::body::HttpResponseBody, response::HttpResponse};
@@ -12,6 +15,13 @@ pub trait RequestHandler: Send + Sync {
     async fn handle(&self, request: FastCGIRequest<'_>) -> HttpResponse;
 }
 
+#[async_trait]
+impl<T: RequestHandler + ?Sized> RequestHandler for Arc<T> {
+    async fn handle(&self, request: FastCGIRequest<'_>) -> HttpResponse {
+        self.as_ref().handle(request).await
+    }
+}
+
 pub fn create_handlers() -> anyhow::Result<Box<dyn RequestHandler>> {
     let mut routes = Vec::new();
 
@@ -19,5 +29,17 @@ pub fn create_handlers() -> anyhow::Result<Box<dyn RequestHandler>> {
 
     routes.append(&mut request_info::create_routes());
 
-    Ok(Box::new(route::Router::new(routes)?))
+    let router = Box::new(route::Router::new(routes)?);
+
+    let context_configuration = crate::config::instance().context_configuration();
+
+    // Global middlewares, outermost first.
+    let mut middlewares: Vec<Arc<dyn middleware::Middleware>> =
+        vec![Arc::new(middleware::RequestLogMiddleware::new())];
+
+    middlewares.append(&mut middleware::response_headers_middlewares(
+        context_configuration.response_headers(),
+    )?);
+
+    Ok(middleware::wrap_handler(middlewares, router))
 }
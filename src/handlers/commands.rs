@@ -1,4 +1,26 @@
-use std::{path::PathBuf, sync::Arc};
+mod arguments;
+mod cache;
+mod dashboard;
+mod history;
+mod jobs;
+mod limits;
+mod output;
+mod process;
+mod redact;
+mod render;
+mod runner;
+mod sandbox;
+mod schedule;
+mod semaphore;
+mod single_flight;
+mod stream;
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    sync::{Arc, Weak},
+};
 
 use anyhow::Context;
 
@@ -6,159 +28,1013 @@ use async_trait::async_trait;
 
 use chrono::prelude::{Local, SecondsFormat};
 
-use log::warn;
+use log::{info, warn};
 
-use tokio::{
-    process::Command,
-    sync::{Semaphore, SemaphorePermit},
-    time::{Duration, Instant},
-};
+use tokio::time::{Duration, Instant};
 
 use serde::Serialize;
 
-use crate::handlers::{
-    route::PathSuffixAndHandler,
-    utils::{build_json_body_response, build_json_response, build_status_code_response},
-    {FastCGIRequest, HttpResponse, HttpResponseBody, RequestHandler},
+use crate::{
+    config::InvalidCommandAction,
+    handlers::{
+        middleware::{response_headers_middlewares, wrap_handler},
+        route::{self, PathSuffixAndHandler},
+        utils::{
+            build_body_response, build_json_error_response, build_json_response,
+            build_json_status_response, build_status_code_response, ResponseFormat,
+        },
+        {FastCGIRequest, HttpResponse, HttpResponseBody, RequestHandler},
+    },
 };
 
 fn current_time_string() -> String {
     Local::now().to_rfc3339_opts(SecondsFormat::Nanos, true)
 }
 
+fn build_unknown_format_response(err: impl std::fmt::Display) -> HttpResponse {
+    build_json_error_response(http::StatusCode::BAD_REQUEST, &err.to_string())
+}
+
 struct AllCommandsHandler {
     json_string: Arc<String>,
+    text_string: Arc<String>,
+    html_string: Arc<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandListEntry<'a> {
+    #[serde(flatten)]
+    command_info: &'a crate::config::CommandInfo,
+    // Why the command was disabled at startup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled: Option<&'a str>,
 }
 
 impl AllCommandsHandler {
-    fn new(commands: &Vec<crate::config::CommandInfo>) -> anyhow::Result<Self> {
-        let json_string = serde_json::to_string(commands)
+    fn new(
+        commands: &[crate::config::CommandInfo],
+        disabled_reasons: &BTreeMap<&str, String>,
+    ) -> anyhow::Result<Self> {
+        let entries: Vec<CommandListEntry<'_>> = commands
+            .iter()
+            .map(|command_info| CommandListEntry {
+                command_info,
+                disabled: disabled_reasons
+                    .get(command_info.id().as_str())
+                    .map(String::as_str),
+            })
+            .collect();
+
+        let json_string = serde_json::to_string(&entries)
             .context("AllCommandsHandler::new: json marshal error")?;
 
         Ok(Self {
             json_string: Arc::new(json_string),
+            text_string: Arc::new(render::all_commands_text(commands, disabled_reasons)),
+            html_string: Arc::new(render::all_commands_html(commands, disabled_reasons)),
         })
     }
 }
 
 #[async_trait]
 impl RequestHandler for AllCommandsHandler {
-    async fn handle(&self, _request: FastCGIRequest<'_>) -> HttpResponse {
-        build_json_body_response(HttpResponseBody::from(Arc::clone(&self.json_string)))
+    async fn handle(&self, request: FastCGIRequest<'_>) -> HttpResponse {
+        let response_format = match ResponseFormat::negotiate(&request) {
+            Err(err) => return build_unknown_format_response(err),
+            Ok(response_format) => response_format,
+        };
+
+        let body_string = match response_format {
+            ResponseFormat::Json => &self.json_string,
+            ResponseFormat::Text => &self.text_string,
+            ResponseFormat::Html => &self.html_string,
+        };
+
+        build_body_response(
+            response_format,
+            HttpResponseBody::from(Arc::clone(body_string)),
+        )
     }
 }
 
-#[derive(thiserror::Error, Debug)]
-enum RunCommandSemaporeAcquireError {
-    #[error("acquire timeout: {0}")]
-    Timeout(#[from] tokio::time::error::Elapsed),
+// Serves the route of a command disabled at startup.
+struct DisabledCommandHandler {
+    error: String,
+}
 
-    #[error("acquire error: {0}")]
-    AcquireError(#[from] tokio::sync::AcquireError),
+#[async_trait]
+impl RequestHandler for DisabledCommandHandler {
+    async fn handle(&self, _request: FastCGIRequest<'_>) -> HttpResponse {
+        build_json_error_response(http::StatusCode::SERVICE_UNAVAILABLE, &self.error)
+    }
 }
 
-struct RunCommandSemapore {
-    semapore: Semaphore,
-    acquire_timeout: Duration,
+// Command ids and dashboard names are used as a uri path segment, so they are limited to
+// unreserved characters.
+fn validate_path_segment(kind: &str, segment: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !segment.is_empty()
+            && segment != "."
+            && segment != ".."
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~')),
+        "{} '{}' is not url-safe, use letters, digits, '-', '.', '_' or '~'",
+        kind,
+        segment
+    );
+    Ok(())
 }
 
-impl RunCommandSemapore {
-    fn new(command_configuration: &crate::config::CommandConfiguration) -> Arc<Self> {
-        Arc::new(Self {
-            semapore: Semaphore::new(*command_configuration.max_concurrent_commands()),
-            acquire_timeout: *command_configuration.semaphore_acquire_timeout(),
-        })
-    }
+struct CommandHistoryHandler {
+    command_info: &'static crate::config::CommandInfo,
+    command_history: Arc<history::CommandHistory>,
+}
 
-    async fn acquire(&self) -> Result<SemaphorePermit<'_>, RunCommandSemaporeAcquireError> {
-        let result = tokio::time::timeout(self.acquire_timeout, self.semapore.acquire()).await?;
+#[async_trait]
+impl RequestHandler for CommandHistoryHandler {
+    async fn handle(&self, request: FastCGIRequest<'_>) -> HttpResponse {
+        let response_format = match ResponseFormat::negotiate(&request) {
+            Err(err) => return build_unknown_format_response(err),
+            Ok(response_format) => response_format,
+        };
 
-        let permit = result?;
+        let entries = self.command_history.entries();
 
-        Ok(permit)
+        match response_format {
+            ResponseFormat::Json => build_json_response(entries),
+            ResponseFormat::Text => build_body_response(
+                response_format,
+                HttpResponseBody::from(render::history_text(&entries)),
+            ),
+            ResponseFormat::Html => build_body_response(
+                response_format,
+                HttpResponseBody::from(render::history_html(self.command_info, &entries)),
+            ),
+        }
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
+struct PipelineStageStatus<'a> {
+    command: &'a str,
+    success: bool,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+}
+
+#[derive(Clone, Debug, Serialize)]
 struct RunCommandResponse<'a> {
     now: String,
     command_duration_ms: u128,
+    // For commands with a cache_ttl, time since the result was stored in the cache.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age_ms: Option<u128>,
+    // True when the result came from a run started by another concurrent identical request.
+    shared: bool,
+    // For scheduled commands, true when the most recent background run failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_run_failed: Option<bool>,
     command_info: &'a crate::config::CommandInfo,
-    command_output: String,
+    args: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    timed_out: bool,
+    cancelled: bool,
+    output_truncated: bool,
+    success: bool,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    // Every stage of a pipeline, the fields above describe the last stage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stages: Option<Vec<PipelineStageStatus<'a>>>,
+    stdout: String,
+    stdout_truncated_bytes: u64,
+    stderr: String,
+    stderr_truncated_bytes: u64,
+    // Number of replacements made by redaction rules, when the command has any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redactions: Option<usize>,
+    // stdout parsed according to the command's output_format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_error: Option<String>,
+}
+
+impl<'a> RunCommandResponse<'a> {
+    fn combined_output(&self) -> String {
+        match &self.error {
+            Some(error) => error.clone(),
+            None => {
+                let mut combined_output =
+                    String::with_capacity(self.stderr.len() + self.stdout.len());
+                combined_output.push_str(&self.stderr);
+                combined_output.push_str(&self.stdout);
+                combined_output
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TooManyRequestsResponse<'a> {
+    error: &'a str,
+    #[serde(flatten)]
+    diagnostics: semaphore::SemaphoreDiagnostics,
+    retry_after_seconds: u64,
+}
+
+// A completed command run, rendered for each request in its negotiated format.
+struct CommandResult {
+    response: RunCommandResponse<'static>,
+    status_code: http::StatusCode,
+}
+
+impl CommandResult {
+    // Runs that timed out or were cancelled are not served from the cache, runs that
+    // exited with a non-zero code are.  Runs that could not start are errors and never
+    // reach the cache.
+    fn is_cacheable(&self) -> bool {
+        !(self.response.timed_out || self.response.cancelled)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("request body is larger than {0} bytes")]
+struct RequestBodyTooLargeError(usize);
+
+// Where the result for a request came from.
+#[derive(Clone, Copy)]
+enum ResultSource {
+    Run {
+        shared: bool,
+    },
+    Stored {
+        stored_time: Instant,
+        last_run_failed: Option<bool>,
+        shared: bool,
+    },
+}
+
+// Why run_command has no result to share or cache.
+enum RunCommandError {
+    Acquire(semaphore::RunCommandSemaporeAcquireError),
+    // The command could not be started, the result describes the error.
+    Run(Box<CommandResult>),
+}
+
+impl std::fmt::Display for RunCommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Acquire(err) => write!(f, "{}", err),
+            Self::Run(command_result) => write!(
+                f,
+                "{}",
+                command_result.response.error.as_deref().unwrap_or_default()
+            ),
+        }
+    }
 }
 
 struct RunCommandHandler {
-    run_command_semaphore: Arc<RunCommandSemapore>,
+    command_semaphores: semaphore::CommandSemaphores,
+    recent_durations: semaphore::RecentDurations,
     command_info: &'static crate::config::CommandInfo,
+    command_arguments: arguments::CommandArguments,
+    command_runner: runner::CommandRunner,
+    output_parser: output::OutputParser,
+    redactor: Arc<redact::Redactor>,
+    // Set when the request body is passed to the command's stdin.
+    max_stdin_bytes: Option<usize>,
+    command_cache: Option<cache::CommandCache<CommandResult>>,
+    single_flight: Option<single_flight::SingleFlight<CommandResult>>,
+    scheduled_command: Option<schedule::ScheduledCommand<CommandResult>>,
+    command_history: Arc<history::CommandHistory>,
+    failure_status_code: http::StatusCode,
+    // Lets streamed runs outlive the request handler call.
+    weak_self: Weak<Self>,
 }
 
 impl RunCommandHandler {
     fn new(
-        run_command_semaphore: Arc<RunCommandSemapore>,
+        command_semaphores: semaphore::CommandSemaphores,
         command_info: &'static crate::config::CommandInfo,
-    ) -> Self {
-        Self {
-            run_command_semaphore,
+        command_history: Arc<history::CommandHistory>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let failure_status_code = match command_info.failure_status_code() {
+            None => http::StatusCode::OK,
+            Some(status_code) => http::StatusCode::from_u16(*status_code)
+                .with_context(|| format!("invalid failure_status_code {}", status_code))?,
+        };
+
+        let command_arguments = arguments::CommandArguments::new(command_info)?;
+
+        let scheduled_command = match command_info.schedule() {
+            None => None,
+            Some(command_schedule) => Some(schedule::ScheduledCommand {
+                schedule: schedule::Schedule::new(command_schedule)?,
+                args: command_arguments
+                    .default_args()
+                    .context("scheduled command arguments error")?,
+                latest_result: schedule::LatestResult::new(),
+            }),
+        };
+
+        anyhow::ensure!(
+            *command_info.cache_max_entries() > 0,
+            "cache_max_entries must be greater than 0"
+        );
+
+        let max_stdin_bytes = match command_info.stdin() {
+            crate::config::CommandStdin::RequestBody => {
+                // Runs with different request bodies can not share a result.
+                anyhow::ensure!(
+                    command_info.cache_ttl().is_none(),
+                    "cache_ttl can not be used with request_body stdin"
+                );
+                Some(
+                    command_info.max_stdin_bytes().unwrap_or(
+                        *crate::config::instance()
+                            .command_configuration()
+                            .max_stdin_bytes(),
+                    ),
+                )
+            }
+            _ => None,
+        };
+
+        let command_runner = runner::CommandRunner::new(command_info)?;
+
+        let output_parser = output::OutputParser::new(command_info.output_format())?;
+
+        let redactor = Arc::new(redact::Redactor::new(
+            crate::config::instance()
+                .command_configuration()
+                .redactions()
+                .iter()
+                .chain(command_info.redactions()),
+        )?);
+
+        Ok(Arc::new_cyclic(|weak_self| Self {
+            command_semaphores,
+            recent_durations: semaphore::RecentDurations::new(),
             command_info,
+            command_arguments,
+            command_runner,
+            output_parser,
+            redactor,
+            max_stdin_bytes,
+            command_cache: command_info
+                .cache_ttl()
+                .map(|ttl| cache::CommandCache::new(ttl, *command_info.cache_max_entries())),
+            single_flight: if *command_info.single_flight() && max_stdin_bytes.is_none() {
+                Some(single_flight::SingleFlight::new())
+            } else {
+                None
+            },
+            scheduled_command,
+            command_history,
+            failure_status_code,
+            weak_self: Weak::clone(weak_self),
+        }))
+    }
+
+    fn build_too_many_requests_response(
+        &self,
+        diagnostics: semaphore::SemaphoreDiagnostics,
+    ) -> HttpResponse {
+        let retry_after_seconds = self
+            .recent_durations
+            .estimate_retry_after_seconds(&diagnostics);
+
+        let mut http_response = build_json_status_response(
+            http::StatusCode::TOO_MANY_REQUESTS,
+            TooManyRequestsResponse {
+                error: "timed out waiting to run command",
+                diagnostics,
+                retry_after_seconds,
+            },
+        );
+
+        http_response.headers_mut().insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from(retry_after_seconds),
+        );
+
+        http_response
+    }
+
+    fn build_run_error_response(
+        &self,
+        err: RunCommandError,
+        response_format: ResponseFormat,
+    ) -> HttpResponse {
+        match err {
+            RunCommandError::Acquire(semaphore::RunCommandSemaporeAcquireError::Timeout(
+                diagnostics,
+            )) => self.build_too_many_requests_response(diagnostics),
+            RunCommandError::Acquire(semaphore::RunCommandSemaporeAcquireError::AcquireError(
+                _,
+            )) => build_status_code_response(http::StatusCode::TOO_MANY_REQUESTS),
+            RunCommandError::Run(command_result) => self.build_http_response(
+                &command_result.response,
+                command_result.status_code,
+                response_format,
+            ),
         }
     }
 
+    async fn acquire_permits(
+        &self,
+        acquire_timeout: Duration,
+    ) -> Result<semaphore::RunCommandPermits, RunCommandError> {
+        self.command_semaphores
+            .acquire(acquire_timeout)
+            .await
+            .map_err(|err| {
+                warn!(
+                    "command '{}' command_semaphores.acquire error: {}",
+                    self.command_info.id(),
+                    err
+                );
+                RunCommandError::Acquire(err)
+            })
+    }
+
+    // Acquires the command's semaphores and runs it.  Fails if the semaphores could not
+    // be acquired or the command could not be started.
     async fn run_command(
         &self,
-        _permit: SemaphorePermit<'_>,
-    ) -> Result<std::process::Output, std::io::Error> {
-        let output = Command::new(self.command_info.command())
-            .args(self.command_info.args())
-            .output()
-            .await?;
+        args: &[String],
+        stdin: &[u8],
+        acquire_timeout: Duration,
+        caller: &str,
+        run_hooks: runner::RunHooks<'_>,
+    ) -> Result<CommandResult, RunCommandError> {
+        let permits = self.acquire_permits(acquire_timeout).await?;
+
+        self.run_with_permits(permits, args, stdin, caller, run_hooks)
+            .await
+    }
+
+    async fn run_with_permits(
+        &self,
+        permits: semaphore::RunCommandPermits,
+        args: &[String],
+        stdin: &[u8],
+        caller: &str,
+        run_hooks: runner::RunHooks<'_>,
+    ) -> Result<CommandResult, RunCommandError> {
+        let command_start_time = Instant::now();
+        let command_result = self.command_runner.run(args, stdin, run_hooks).await;
+        let command_duration = command_start_time.elapsed();
+
+        drop(permits);
 
-        Ok(output)
+        self.recent_durations.record(command_duration);
+
+        let command_result =
+            self.handle_command_result(args.to_vec(), command_result, command_duration);
+
+        self.command_history
+            .record(caller, &command_result.response);
+
+        if command_result.response.error.is_some() {
+            return Err(RunCommandError::Run(Box::new(command_result)));
+        }
+
+        Ok(command_result)
+    }
+
+    // Starts the command once its semaphores are acquired and returns a response whose
+    // body is the output as it is produced, followed by a summary of the run.
+    async fn run_command_streamed(
+        &self,
+        request: &FastCGIRequest<'_>,
+        args: Vec<String>,
+        stdin: Vec<u8>,
+        acquire_timeout: Duration,
+        stream_format: crate::config::StreamFormat,
+        response_format: ResponseFormat,
+    ) -> HttpResponse {
+        let permits = match self.acquire_permits(acquire_timeout).await {
+            Err(err) => return self.build_run_error_response(err, response_format),
+            Ok(permits) => permits,
+        };
+
+        let handler = match self.weak_self.upgrade() {
+            None => return build_status_code_response(http::StatusCode::SERVICE_UNAVAILABLE),
+            Some(handler) => handler,
+        };
+
+        let caller = history::request_caller(request);
+
+        // The run outlives the handler, so it follows the request's cancellation itself.
+        let cancellation_token = request.cancellation_token().clone();
+
+        let (output_streamer, receiver) =
+            stream::OutputStreamer::new(stream_format, Arc::clone(&self.redactor));
+
+        tokio::spawn(async move {
+            let result = handler
+                .run_with_permits(
+                    permits,
+                    &args,
+                    &stdin,
+                    &caller,
+                    runner::RunHooks {
+                        output_observer: Some(&output_streamer),
+                        cancellation_token: Some(&cancellation_token),
+                    },
+                )
+                .await;
+
+            match result {
+                Ok(command_result) => output_streamer.finish(&command_result.response),
+                Err(RunCommandError::Run(command_result)) => {
+                    output_streamer.finish(&command_result.response)
+                }
+                Err(RunCommandError::Acquire(_)) => {}
+            }
+        });
+
+        stream::build_stream_response(stream_format, receiver)
+    }
+
+    // The request body for commands with request_body stdin, otherwise nothing.
+    fn command_stdin<'r>(
+        &self,
+        request: &'r FastCGIRequest<'_>,
+    ) -> Result<&'r [u8], RequestBodyTooLargeError> {
+        match self.max_stdin_bytes {
+            None => Ok(&[]),
+            Some(max_stdin_bytes) if request.body().len() > max_stdin_bytes => {
+                Err(RequestBodyTooLargeError(max_stdin_bytes))
+            }
+            Some(_) => Ok(request.body()),
+        }
+    }
+
+    // Runs the command, joining an identical run already in flight unless single_flight is
+    // disabled.  Returns the result and whether it was shared.
+    async fn run_command_single_flight(
+        &self,
+        args: &[String],
+        acquire_timeout: Duration,
+        caller: &str,
+    ) -> Result<(Arc<CommandResult>, bool), RunCommandError> {
+        let run_hooks = runner::RunHooks::default();
+
+        match &self.single_flight {
+            None => self
+                .run_command(args, &[], acquire_timeout, caller, run_hooks)
+                .await
+                .map(|command_result| (Arc::new(command_result), false)),
+            Some(single_flight) => {
+                single_flight
+                    .run(args, || {
+                        self.run_command(args, &[], acquire_timeout, caller, run_hooks)
+                    })
+                    .await
+            }
+        }
     }
 
     fn handle_command_result(
         &self,
-        command_result: Result<std::process::Output, std::io::Error>,
+        args: Vec<String>,
+        command_result: Result<runner::CommandRunOutput, std::io::Error>,
         command_duration: Duration,
-    ) -> HttpResponse {
-        let response = RunCommandResponse {
+    ) -> CommandResult {
+        let mut response = RunCommandResponse {
             now: current_time_string(),
             command_duration_ms: command_duration.as_millis(),
-            command_info: &self.command_info,
-            command_output: match command_result {
-                Err(err) => {
-                    format!("error running command {}", err)
+            age_ms: None,
+            shared: false,
+            last_run_failed: None,
+            command_info: self.command_info,
+            args,
+            error: None,
+            timed_out: false,
+            cancelled: false,
+            output_truncated: false,
+            success: false,
+            exit_code: None,
+            signal: None,
+            stages: None,
+            stdout: String::new(),
+            stdout_truncated_bytes: 0,
+            stderr: String::new(),
+            stderr_truncated_bytes: 0,
+            redactions: None,
+            output: None,
+            output_error: None,
+        };
+
+        let status_code = match command_result {
+            Err(err) => {
+                warn!(
+                    "command '{}' failed to run: {}",
+                    self.command_info.id(),
+                    err
+                );
+                response.error = Some(format!("error running command {}", err));
+                http::StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Ok(command_output) => {
+                if let Some(status) = command_output.status {
+                    response.success =
+                        status.success() && !command_output.timed_out && !command_output.cancelled;
+                    response.exit_code = status.code();
+                    response.signal = status.signal();
                 }
-                Ok(command_output) => {
-                    let mut combined_output = String::with_capacity(
-                        command_output.stderr.len() + command_output.stdout.len(),
+                if !command_output.stage_statuses.is_empty() {
+                    let stage_commands = std::iter::once(self.command_info.command()).chain(
+                        self.command_info
+                            .pipeline()
+                            .iter()
+                            .map(|stage| stage.command()),
+                    );
+                    response.stages = Some(
+                        stage_commands
+                            .zip(&command_output.stage_statuses)
+                            .map(|(command, status)| PipelineStageStatus {
+                                command,
+                                success: status.success(),
+                                exit_code: status.code(),
+                                signal: status.signal(),
+                            })
+                            .collect(),
                     );
-                    combined_output.push_str(&String::from_utf8_lossy(&command_output.stderr));
-                    combined_output.push_str(&String::from_utf8_lossy(&command_output.stdout));
-                    combined_output
                 }
-            },
+                response.timed_out = command_output.timed_out;
+                response.cancelled = command_output.cancelled;
+                response.output_truncated = command_output.output_limit_exceeded;
+                response.stdout = String::from_utf8_lossy(&command_output.stdout.data).into_owned();
+                response.stdout_truncated_bytes = command_output.stdout.truncated_bytes;
+                response.stderr = String::from_utf8_lossy(&command_output.stderr.data).into_owned();
+                response.stderr_truncated_bytes = command_output.stderr.truncated_bytes;
+                if !self.redactor.is_empty() {
+                    let (stdout, stdout_redactions) = self.redactor.redact(&response.stdout);
+                    let (stderr, stderr_redactions) = self.redactor.redact(&response.stderr);
+                    response.stdout = stdout;
+                    response.stderr = stderr;
+                    response.redactions = Some(stdout_redactions + stderr_redactions);
+                }
+                // Output of an interrupted run is incomplete, so it is not parsed.
+                if !response.timed_out && !response.cancelled {
+                    match self.output_parser.parse(&response.stdout) {
+                        Err(err) => {
+                            warn!(
+                                "command '{}' output parse error: {}",
+                                self.command_info.id(),
+                                err
+                            );
+                            response.output_error = Some(err.to_string());
+                        }
+                        Ok(output) => {
+                            if output.is_some() && !*self.command_info.include_stdout() {
+                                response.stdout.clear();
+                            }
+                            response.output = output;
+                        }
+                    }
+                }
+                if response.timed_out {
+                    http::StatusCode::GATEWAY_TIMEOUT
+                } else if response.success {
+                    http::StatusCode::OK
+                } else {
+                    self.failure_status_code
+                }
+            }
         };
 
-        build_json_response(response)
+        CommandResult {
+            response,
+            status_code,
+        }
+    }
+
+    fn build_http_response(
+        &self,
+        response: &RunCommandResponse<'_>,
+        status_code: http::StatusCode,
+        response_format: ResponseFormat,
+    ) -> HttpResponse {
+        let mut http_response = match response_format {
+            ResponseFormat::Json => build_json_response(response),
+            ResponseFormat::Text => build_body_response(
+                response_format,
+                HttpResponseBody::from(response.combined_output()),
+            ),
+            ResponseFormat::Html => build_body_response(
+                response_format,
+                HttpResponseBody::from(render::run_command_html(response)),
+            ),
+        };
+
+        if http_response.status() == http::StatusCode::OK {
+            *http_response.status_mut() = status_code;
+        }
+
+        http_response
+    }
+
+    fn build_stored_http_response(
+        &self,
+        command_result: &CommandResult,
+        stored_time: Instant,
+        response: RunCommandResponse<'static>,
+        response_format: ResponseFormat,
+    ) -> HttpResponse {
+        let age = stored_time.elapsed();
+
+        let mut http_response =
+            self.build_http_response(&response, command_result.status_code, response_format);
+
+        http_response
+            .headers_mut()
+            .insert(http::header::AGE, http::HeaderValue::from(age.as_secs()));
+
+        http_response
+    }
+
+    // Serves a stored result from the schedule or cache when there is one, otherwise runs
+    // the command.
+    async fn get_result(
+        &self,
+        args: &[String],
+        cache_mode: cache::CacheMode,
+        acquire_timeout: Duration,
+        caller: &str,
+    ) -> Result<(Arc<CommandResult>, ResultSource), RunCommandError> {
+        // Requests for the scheduled arguments are served from the latest background run.
+        if let Some(scheduled_command) = &self.scheduled_command {
+            if cache_mode == cache::CacheMode::Use && args == scheduled_command.args {
+                if let Some(snapshot) = scheduled_command.latest_result.get() {
+                    return Ok((
+                        snapshot.value,
+                        ResultSource::Stored {
+                            stored_time: snapshot.stored_time,
+                            last_run_failed: Some(snapshot.last_run_failed),
+                            shared: false,
+                        },
+                    ));
+                }
+            }
+        }
+
+        let command_cache = match &self.command_cache {
+            Some(command_cache) if cache_mode != cache::CacheMode::Bypass => command_cache,
+            _ => {
+                return self
+                    .run_command_single_flight(args, acquire_timeout, caller)
+                    .await
+                    .map(|(command_result, shared)| {
+                        (command_result, ResultSource::Run { shared })
+                    });
+            }
+        };
+
+        if cache_mode == cache::CacheMode::Purge {
+            command_cache.purge();
+        }
+
+        command_cache
+            .get_or_run(
+                args,
+                || self.run_command_single_flight(args, acquire_timeout, caller),
+                CommandResult::is_cacheable,
+            )
+            .await
+            .map(|cache_result| match cache_result {
+                cache::CacheResult::Stored { entry, shared } => (
+                    entry.value,
+                    ResultSource::Stored {
+                        stored_time: entry.stored_time,
+                        last_run_failed: None,
+                        shared,
+                    },
+                ),
+                cache::CacheResult::Uncached { value, shared } => {
+                    (value, ResultSource::Run { shared })
+                }
+            })
+    }
+
+    // The response with the fields describing where it came from filled in.
+    fn result_response(
+        command_result: &CommandResult,
+        result_source: ResultSource,
+    ) -> RunCommandResponse<'static> {
+        let mut response = command_result.response.clone();
+        match result_source {
+            ResultSource::Run { shared } => response.shared = shared,
+            ResultSource::Stored {
+                stored_time,
+                last_run_failed,
+                shared,
+            } => {
+                response.age_ms = Some(stored_time.elapsed().as_millis());
+                response.last_run_failed = last_run_failed;
+                response.shared = shared;
+            }
+        }
+        response
+    }
+
+    fn build_result_http_response(
+        &self,
+        command_result: &CommandResult,
+        result_source: ResultSource,
+        response_format: ResponseFormat,
+    ) -> HttpResponse {
+        match result_source {
+            ResultSource::Run { shared: false } => self.build_http_response(
+                &command_result.response,
+                command_result.status_code,
+                response_format,
+            ),
+            ResultSource::Run { shared: true } => self.build_http_response(
+                &Self::result_response(command_result, result_source),
+                command_result.status_code,
+                response_format,
+            ),
+            ResultSource::Stored { stored_time, .. } => self.build_stored_http_response(
+                command_result,
+                stored_time,
+                Self::result_response(command_result, result_source),
+                response_format,
+            ),
+        }
+    }
+
+    // Runs a scheduled command in the background for the life of the server.
+    async fn run_schedule(self: Arc<Self>) {
+        let scheduled_command = match &self.scheduled_command {
+            None => return,
+            Some(scheduled_command) => scheduled_command,
+        };
+
+        let acquire_timeout = self.command_semaphores.acquire_timeout(None);
+
+        // Run once at startup so a result is available quickly.
+        let mut run_time = Instant::now();
+
+        loop {
+            let result = self
+                .run_command_single_flight(
+                    &scheduled_command.args,
+                    acquire_timeout,
+                    history::SCHEDULE_CALLER,
+                )
+                .await;
+
+            match result {
+                Err(err) => {
+                    warn!(
+                        "scheduled command '{}' failed to run: {}",
+                        self.command_info.id(),
+                        err
+                    );
+                    scheduled_command.latest_result.record(None, false);
+                }
+                Ok((command_result, _)) => {
+                    let success = command_result.response.success;
+                    if !success {
+                        warn!("scheduled command '{}' run failed", self.command_info.id());
+                    }
+                    scheduled_command
+                        .latest_result
+                        .record(Some(command_result), success);
+                }
+            }
+
+            run_time = match scheduled_command.schedule.next_run_time(run_time) {
+                None => {
+                    info!(
+                        "scheduled command '{}' has no more run times",
+                        self.command_info.id()
+                    );
+                    return;
+                }
+                Some(next_run_time) => next_run_time,
+            };
+
+            tokio::time::sleep_until(run_time).await;
+        }
     }
 }
 
 #[async_trait]
 impl RequestHandler for RunCommandHandler {
-    async fn handle(&self, _request: FastCGIRequest<'_>) -> HttpResponse {
-        let permit = match self.run_command_semaphore.acquire().await {
+    async fn handle(&self, request: FastCGIRequest<'_>) -> HttpResponse {
+        let response_format = match ResponseFormat::negotiate(&request) {
+            Err(err) => return build_unknown_format_response(err),
+            Ok(response_format) => response_format,
+        };
+
+        let args = match self.command_arguments.build(&request) {
             Err(err) => {
-                warn!("run_command_semaphore.acquire error: {}", err);
-                return build_status_code_response(http::StatusCode::TOO_MANY_REQUESTS);
+                return build_json_error_response(http::StatusCode::BAD_REQUEST, &err.to_string())
             }
-            Ok(permit) => permit,
+            Ok(args) => args,
         };
 
-        let command_start_time = Instant::now();
-        let command_result = self.run_command(permit).await;
-        let command_duration = command_start_time.elapsed();
+        let stdin = match self.command_stdin(&request) {
+            Err(err) => {
+                return build_json_error_response(
+                    http::StatusCode::PAYLOAD_TOO_LARGE,
+                    &err.to_string(),
+                )
+            }
+            Ok(stdin) => stdin,
+        };
+
+        let requested_acquire_timeout = match request
+            .query_param("wait")
+            .map(|wait| humantime::parse_duration(&wait))
+            .transpose()
+        {
+            Err(err) => {
+                return build_json_error_response(
+                    http::StatusCode::BAD_REQUEST,
+                    &format!("invalid wait: {}", err),
+                )
+            }
+            Ok(requested_acquire_timeout) => requested_acquire_timeout,
+        };
+
+        let cache_mode = match cache::CacheMode::parse(request.query_param("cache").as_deref()) {
+            Err(err) => {
+                return build_json_error_response(http::StatusCode::BAD_REQUEST, &err.to_string())
+            }
+            Ok(cache_mode) => cache_mode,
+        };
 
-        self.handle_command_result(command_result, command_duration)
+        let acquire_timeout = self
+            .command_semaphores
+            .acquire_timeout(requested_acquire_timeout);
+
+        let stream_format =
+            match stream::requested_stream_format(&request, *self.command_info.stream()) {
+                Err(err) => {
+                    return build_json_error_response(
+                        http::StatusCode::BAD_REQUEST,
+                        &err.to_string(),
+                    )
+                }
+                Ok(stream_format) => stream_format,
+            };
+
+        // Streamed output belongs to one client, so it is never shared or cached.
+        if let Some(stream_format) = stream_format {
+            return self
+                .run_command_streamed(
+                    &request,
+                    args,
+                    stdin.to_vec(),
+                    acquire_timeout,
+                    stream_format,
+                    response_format,
+                )
+                .await;
+        }
+
+        let caller = history::request_caller(&request);
+
+        // Each run gets its own request body, so it is not shared or served from a schedule.
+        if self.max_stdin_bytes.is_some() {
+            return match self
+                .run_command(
+                    &args,
+                    stdin,
+                    acquire_timeout,
+                    &caller,
+                    runner::RunHooks::default(),
+                )
+                .await
+            {
+                Err(err) => self.build_run_error_response(err, response_format),
+                Ok(command_result) => self.build_http_response(
+                    &command_result.response,
+                    command_result.status_code,
+                    response_format,
+                ),
+            };
+        }
+
+        match self
+            .get_result(&args, cache_mode, acquire_timeout, &caller)
+            .await
+        {
+            Err(err) => self.build_run_error_response(err, response_format),
+            Ok((command_result, result_source)) => {
+                self.build_result_http_response(&command_result, result_source, response_format)
+            }
+        }
     }
 }
 
@@ -166,26 +1042,168 @@ pub fn create_routes() -> anyhow::Result<Vec<PathSuffixAndHandler>> {
     let command_configuration = crate::config::instance().command_configuration();
 
     let mut routes: Vec<PathSuffixAndHandler> =
-        Vec::with_capacity(1 + command_configuration.commands().len());
+        Vec::with_capacity(3 + 4 * command_configuration.commands().len());
 
-    routes.push((
-        PathBuf::from("commands"),
-        Box::new(AllCommandsHandler::new(command_configuration.commands())?),
+    let mut command_ids = HashSet::with_capacity(command_configuration.commands().len());
+
+    for command_info in command_configuration.commands() {
+        validate_path_segment("command id", command_info.id())?;
+        anyhow::ensure!(
+            command_ids.insert(command_info.id().as_str()),
+            "duplicate command id '{}'",
+            command_info.id()
+        );
+    }
+
+    let command_semaphores_factory =
+        semaphore::CommandSemaphoresFactory::new(command_configuration);
+
+    let job_store = Arc::new(jobs::JobStore::new(
+        *command_configuration.max_jobs(),
+        *command_configuration.job_retention(),
     ));
 
-    let run_command_semaphore = RunCommandSemapore::new(command_configuration);
+    let context_configuration = crate::config::instance().context_configuration();
+
+    // Global rules are part of every command, so an invalid one stops startup even when
+    // invalid commands are disabled.
+    redact::Redactor::new(command_configuration.redactions())
+        .context("redactions configuration error")?;
+
+    let mut run_command_handlers = BTreeMap::new();
+
+    let mut disabled_reasons = BTreeMap::new();
 
     for command_info in command_configuration.commands() {
         let path_suffix = PathBuf::from("commands").join(command_info.id());
 
+        let command_history = Arc::new(history::CommandHistory::new(
+            command_info
+                .history_size()
+                .unwrap_or(*command_configuration.history_size()),
+            *command_info.history_include_output(),
+        ));
+
+        let (handler, middlewares) = match command_semaphores_factory
+            .command_semaphores(command_info)
+            .and_then(|command_semaphores| {
+                RunCommandHandler::new(
+                    command_semaphores,
+                    command_info,
+                    Arc::clone(&command_history),
+                )
+            })
+            .and_then(|handler| {
+                let middlewares = response_headers_middlewares(command_info.response_headers())
+                    .context("response_headers error")?;
+                Ok((handler, middlewares))
+            }) {
+            Ok(handler_and_middlewares) => handler_and_middlewares,
+            Err(err)
+                if *command_configuration.invalid_commands() == InvalidCommandAction::Disable =>
+            {
+                let reason = format!("{:#}", err);
+                warn!("command '{}' disabled: {}", command_info.id(), reason);
+
+                let disabled_handler = Arc::new(DisabledCommandHandler {
+                    error: format!("command is disabled: {}", reason),
+                });
+
+                for disabled_path_suffix in [
+                    path_suffix.join("history"),
+                    path_suffix.join("jobs").join(route::WILDCARD_SEGMENT),
+                    path_suffix.join("jobs"),
+                    path_suffix,
+                ] {
+                    routes.push((
+                        disabled_path_suffix,
+                        Box::new(Arc::clone(&disabled_handler)),
+                    ));
+                }
+
+                run_command_handlers.insert(command_info.id().as_str(), Err(reason.clone()));
+                disabled_reasons.insert(command_info.id().as_str(), reason);
+                continue;
+            }
+            Err(err) => {
+                return Err(err.context(format!(
+                    "command '{}' configuration error",
+                    command_info.id()
+                )))
+            }
+        };
+
+        if handler.scheduled_command.is_some() {
+            tokio::spawn(Arc::clone(&handler).run_schedule());
+        }
+
         routes.push((
-            path_suffix,
-            Box::new(RunCommandHandler::new(
-                Arc::clone(&run_command_semaphore),
-                command_info,
-            )),
+            path_suffix.join("history"),
+            wrap_handler(
+                middlewares.clone(),
+                Box::new(CommandHistoryHandler {
+                    command_info,
+                    command_history,
+                }),
+            ),
+        ));
+
+        let jobs_path_suffix = path_suffix.join("jobs");
+
+        let jobs_handler = Arc::new(jobs::CommandJobsHandler::new(
+            Arc::clone(&handler),
+            Arc::clone(&job_store),
+            format!(
+                "{}/{}",
+                context_configuration.context(),
+                jobs_path_suffix.display()
+            ),
+        ));
+
+        routes.push((
+            jobs_path_suffix.join(route::WILDCARD_SEGMENT),
+            wrap_handler(middlewares.clone(), Box::new(Arc::clone(&jobs_handler))),
+        ));
+
+        routes.push((
+            jobs_path_suffix,
+            wrap_handler(middlewares.clone(), Box::new(jobs_handler)),
         ));
+
+        run_command_handlers.insert(command_info.id().as_str(), Ok(Arc::clone(&handler)));
+
+        routes.push((path_suffix, wrap_handler(middlewares, Box::new(handler))));
     }
 
+    routes.push((
+        PathBuf::from("commands"),
+        Box::new(AllCommandsHandler::new(
+            command_configuration.commands(),
+            &disabled_reasons,
+        )?),
+    ));
+
+    let dashboard_path_suffix = PathBuf::from("dashboard");
+
+    let dashboard_handler = Arc::new(
+        dashboard::DashboardHandler::new(
+            run_command_handlers,
+            command_configuration.dashboards(),
+            format!(
+                "{}/{}",
+                context_configuration.context(),
+                dashboard_path_suffix.display()
+            ),
+        )
+        .context("dashboards configuration error")?,
+    );
+
+    routes.push((
+        dashboard_path_suffix.join(route::WILDCARD_SEGMENT),
+        Box::new(Arc::clone(&dashboard_handler)),
+    ));
+
+    routes.push((dashboard_path_suffix, Box::new(dashboard_handler)));
+
     Ok(routes)
 }
@@ -0,0 +1,342 @@
+use anyhow::Context;
+
+use regex::Regex;
+
+use crate::{
+    config::{CommandInfo, CommandParameter, CommandParameterType},
+    request::FastCGIRequest,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum CommandArgumentsError {
+    #[error("missing required parameter '{0}'")]
+    MissingParameter(String),
+
+    #[error("invalid value for parameter '{name}': {reason}")]
+    InvalidParameter { name: String, reason: String },
+}
+
+enum ParameterValidator {
+    Integer { min: Option<i64>, max: Option<i64> },
+    Enum { values: &'static [String] },
+    String { pattern: &'static str, regex: Regex },
+}
+
+impl ParameterValidator {
+    fn new(parameter_type: &'static CommandParameterType) -> anyhow::Result<Self> {
+        Ok(match parameter_type {
+            CommandParameterType::Integer { min, max } => {
+                if let (Some(min), Some(max)) = (min, max) {
+                    anyhow::ensure!(min <= max, "min {} is greater than max {}", min, max);
+                }
+                Self::Integer {
+                    min: *min,
+                    max: *max,
+                }
+            }
+            CommandParameterType::Enum { values } => {
+                anyhow::ensure!(!values.is_empty(), "enum values must not be empty");
+                Self::Enum { values }
+            }
+            CommandParameterType::String { pattern } => {
+                // The pattern must match the entire value.
+                let regex = Regex::new(&format!("^(?:{})$", pattern))
+                    .with_context(|| format!("invalid pattern '{}'", pattern))?;
+                Self::String { pattern, regex }
+            }
+        })
+    }
+
+    // Returns the value to pass as the argument.
+    fn validate(&self, value: &str) -> Result<String, String> {
+        match self {
+            Self::Integer { min, max } => {
+                let integer: i64 = value
+                    .parse()
+                    .map_err(|_| format!("'{}' is not an integer", value))?;
+                if let Some(min) = min {
+                    if integer < *min {
+                        return Err(format!("{} is less than minimum {}", integer, min));
+                    }
+                }
+                if let Some(max) = max {
+                    if integer > *max {
+                        return Err(format!("{} is greater than maximum {}", integer, max));
+                    }
+                }
+                Ok(integer.to_string())
+            }
+            Self::Enum { values } => {
+                if values.iter().any(|v| v == value) {
+                    Ok(value.to_owned())
+                } else {
+                    Err(format!("'{}' is not one of [{}]", value, values.join(", ")))
+                }
+            }
+            Self::String { pattern, regex } => {
+                if regex.is_match(value) {
+                    Ok(value.to_owned())
+                } else {
+                    Err(format!("'{}' does not match pattern '{}'", value, pattern))
+                }
+            }
+        }
+    }
+}
+
+struct Parameter {
+    config: &'static CommandParameter,
+    validator: ParameterValidator,
+}
+
+impl Parameter {
+    fn value(&self, supplied: Option<String>) -> Result<Option<String>, CommandArgumentsError> {
+        let name = self.config.name();
+
+        let value = match supplied {
+            Some(value) => value,
+            None => match self.config.default() {
+                Some(default) => default.clone(),
+                None if *self.config.required() => {
+                    return Err(CommandArgumentsError::MissingParameter(name.clone()))
+                }
+                None => return Ok(None),
+            },
+        };
+
+        self.validator.validate(&value).map(Some).map_err(|reason| {
+            CommandArgumentsError::InvalidParameter {
+                name: name.clone(),
+                reason,
+            }
+        })
+    }
+}
+
+// Builds the argument list for a command from its fixed args and any parameters
+// supplied with the request.  Values are passed directly as arguments, never through a shell.
+pub struct CommandArguments {
+    args: &'static [String],
+    parameters: Vec<Parameter>,
+}
+
+impl CommandArguments {
+    pub fn new(command_info: &'static CommandInfo) -> anyhow::Result<Self> {
+        let args = command_info.args();
+
+        let mut parameters = Vec::with_capacity(command_info.parameters().len());
+
+        for parameter_config in command_info.parameters() {
+            let name = parameter_config.name();
+
+            anyhow::ensure!(
+                *parameter_config.position() <= args.len(),
+                "parameter '{}' position {} is past the end of args",
+                name,
+                parameter_config.position(),
+            );
+
+            anyhow::ensure!(
+                !parameters
+                    .iter()
+                    .any(|p: &Parameter| p.config.name() == name),
+                "duplicate parameter '{}'",
+                name,
+            );
+
+            let validator = ParameterValidator::new(parameter_config.parameter_type())
+                .with_context(|| format!("parameter '{}' error", name))?;
+
+            if let Some(default) = parameter_config.default() {
+                validator.validate(default).map_err(|reason| {
+                    anyhow::anyhow!("parameter '{}' invalid default: {}", name, reason)
+                })?;
+            }
+
+            parameters.push(Parameter {
+                config: parameter_config,
+                validator,
+            });
+        }
+
+        Ok(Self { args, parameters })
+    }
+
+    pub fn build(
+        &self,
+        request: &FastCGIRequest<'_>,
+    ) -> Result<Vec<String>, CommandArgumentsError> {
+        self.build_from(|name| request.query_or_form_param(name))
+    }
+
+    // Arguments using only parameter defaults, for runs without a request.
+    pub fn default_args(&self) -> Result<Vec<String>, CommandArgumentsError> {
+        self.build_from(|_| None)
+    }
+
+    fn build_from(
+        &self,
+        supplied_value: impl Fn(&str) -> Option<String>,
+    ) -> Result<Vec<String>, CommandArgumentsError> {
+        if self.parameters.is_empty() {
+            return Ok(self.args.to_vec());
+        }
+
+        let mut parameter_values = Vec::with_capacity(self.parameters.len());
+        for parameter in &self.parameters {
+            parameter_values.push((
+                *parameter.config.position(),
+                parameter.value(supplied_value(parameter.config.name()))?,
+            ));
+        }
+
+        let mut args = Vec::with_capacity(self.args.len() + parameter_values.len());
+
+        for position in 0..=self.args.len() {
+            for (parameter_position, value) in parameter_values.iter_mut() {
+                if *parameter_position == position {
+                    if let Some(value) = value.take() {
+                        args.push(value);
+                    }
+                }
+            }
+
+            if let Some(arg) = self.args.get(position) {
+                args.push(arg.clone());
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn command_arguments(command_info_json: serde_json::Value) -> anyhow::Result<CommandArguments> {
+        CommandArguments::new(crate::config::leak_test_fixture(command_info_json))
+    }
+
+    fn build(
+        command_arguments: &CommandArguments,
+        supplied: &[(&str, &str)],
+    ) -> Result<Vec<String>, CommandArgumentsError> {
+        let supplied: HashMap<&str, &str> = supplied.iter().copied().collect();
+        command_arguments.build_from(|name| supplied.get(name).map(|value| (*value).to_owned()))
+    }
+
+    fn ping_arguments() -> CommandArguments {
+        command_arguments(serde_json::json!({
+            "id": "ping",
+            "description": "ping",
+            "command": "/bin/ping",
+            "args": ["-c", "host"],
+            "parameters": [
+                {"name": "count", "type": "integer", "min": 1, "max": 5, "position": 1, "default": "3"},
+                {"name": "family", "type": "enum", "values": ["-4", "-6"], "position": 0},
+                {"name": "host", "type": "string", "pattern": "[a-z.]+", "position": 2, "required": true},
+                {"name": "last", "type": "string", "pattern": "x", "position": 2},
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn inserts_values_at_positions() {
+        let command_arguments = ping_arguments();
+
+        assert_eq!(
+            build(&command_arguments, &[("host", "example.com")]).unwrap(),
+            vec!["-c", "3", "host", "example.com"]
+        );
+
+        // Parameters at the same position keep their configured order.
+        assert_eq!(
+            build(
+                &command_arguments,
+                &[
+                    ("host", "example.com"),
+                    ("family", "-6"),
+                    ("count", "5"),
+                    ("last", "x")
+                ]
+            )
+            .unwrap(),
+            vec!["-6", "-c", "5", "host", "example.com", "x"]
+        );
+    }
+
+    #[test]
+    fn rejects_missing_and_invalid_values() {
+        let command_arguments = ping_arguments();
+
+        assert!(matches!(
+            build(&command_arguments, &[]),
+            Err(CommandArgumentsError::MissingParameter(name)) if name == "host"
+        ));
+
+        for supplied in [
+            [("host", "example.com"), ("count", "6")],
+            [("host", "example.com"), ("count", "three")],
+            [("host", "example.com"), ("family", "-5")],
+            [("host", "example.com; rm"), ("count", "1")],
+        ] {
+            assert!(
+                matches!(
+                    build(&command_arguments, &supplied),
+                    Err(CommandArgumentsError::InvalidParameter { .. })
+                ),
+                "{:?} was accepted",
+                supplied
+            );
+        }
+    }
+
+    #[test]
+    fn integer_values_are_normalized() {
+        assert_eq!(
+            build(&ping_arguments(), &[("host", "a"), ("count", "+2")]).unwrap(),
+            vec!["-c", "2", "host", "a"]
+        );
+    }
+
+    #[test]
+    fn without_parameters_args_are_unchanged() {
+        let command_arguments = command_arguments(serde_json::json!({
+            "id": "uptime",
+            "description": "uptime",
+            "command": "/usr/bin/uptime",
+            "args": ["-p"],
+        }))
+        .unwrap();
+
+        assert_eq!(command_arguments.default_args().unwrap(), vec!["-p"]);
+    }
+
+    #[test]
+    fn rejects_invalid_parameter_configuration() {
+        for parameter in [
+            serde_json::json!({"name": "n", "type": "integer", "position": 2}),
+            serde_json::json!({"name": "n", "type": "integer", "min": 5, "max": 1, "position": 0}),
+            serde_json::json!({"name": "n", "type": "enum", "values": [], "position": 0}),
+            serde_json::json!({"name": "n", "type": "string", "pattern": "(", "position": 0}),
+            serde_json::json!({"name": "n", "type": "integer", "max": 1, "position": 0, "default": "2"}),
+        ] {
+            assert!(
+                command_arguments(serde_json::json!({
+                    "id": "c",
+                    "description": "c",
+                    "command": "/bin/true",
+                    "args": ["a"],
+                    "parameters": [parameter],
+                }))
+                .is_err(),
+                "{} was accepted",
+                parameter
+            );
+        }
+    }
+}
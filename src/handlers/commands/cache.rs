@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use tokio::time::{Duration, Instant};
+
+#[derive(thiserror::Error, Debug)]
+#[error("unknown cache mode '{0}', expected bypass or purge")]
+pub struct UnknownCacheModeError(String);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    Use,
+    // Run the command without reading or storing a cached result.
+    Bypass,
+    // Drop all cached results for the command, then run and cache as usual.
+    Purge,
+}
+
+impl CacheMode {
+    pub fn parse(value: Option<&str>) -> Result<Self, UnknownCacheModeError> {
+        match value {
+            None => Ok(Self::Use),
+            Some("bypass") => Ok(Self::Bypass),
+            Some("purge") => Ok(Self::Purge),
+            Some(value) => Err(UnknownCacheModeError(value.to_owned())),
+        }
+    }
+}
+
+pub struct CacheEntry<T> {
+    pub value: Arc<T>,
+    pub stored_time: Instant,
+}
+
+impl<T> Clone for CacheEntry<T> {
+    fn clone(&self) -> Self {
+        Self {
+            value: Arc::clone(&self.value),
+            stored_time: self.stored_time,
+        }
+    }
+}
+
+// Each result tells whether it came from a run started by another concurrent request.
+pub enum CacheResult<T> {
+    Stored { entry: CacheEntry<T>, shared: bool },
+    // A run result that was not cacheable.
+    Uncached { value: Arc<T>, shared: bool },
+}
+
+type CacheSlot<T> = Arc<tokio::sync::Mutex<Option<CacheEntry<T>>>>;
+
+// Results of a command keyed by its arguments.  Each key has its own async lock, so
+// concurrent requests on a miss wait for a single run and then share its result.
+pub struct CommandCache<T> {
+    ttl: Duration,
+    max_entries: usize,
+    slots: Mutex<HashMap<Vec<String>, CacheSlot<T>>>,
+}
+
+impl<T> CommandCache<T> {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn purge(&self) {
+        self.slots.lock().unwrap().clear();
+    }
+
+    fn is_fresh(&self, entry: &CacheEntry<T>) -> bool {
+        entry.stored_time.elapsed() < self.ttl
+    }
+
+    fn slot(&self, args: &[String]) -> CacheSlot<T> {
+        let mut slots = self.slots.lock().unwrap();
+
+        // Drop expired entries that no request is using.
+        slots.retain(|_, slot| {
+            if Arc::strong_count(slot) > 1 {
+                return true;
+            }
+            match slot.try_lock() {
+                Err(_) => true,
+                Ok(entry) => entry.as_ref().is_some_and(|entry| self.is_fresh(entry)),
+            }
+        });
+
+        if slots.len() >= self.max_entries && !slots.contains_key(args) {
+            // Arguments constrained only by a pattern can have any number of values.
+            let oldest_args = slots
+                .iter()
+                .filter(|(_, slot)| Arc::strong_count(slot) == 1)
+                .filter_map(|(args, slot)| {
+                    let stored_time = slot.try_lock().ok()?.as_ref()?.stored_time;
+                    Some((args, stored_time))
+                })
+                .min_by_key(|(_, stored_time)| *stored_time)
+                .map(|(args, _)| args.clone());
+
+            if let Some(oldest_args) = oldest_args {
+                slots.remove(&oldest_args);
+            }
+        }
+
+        Arc::clone(slots.entry(args.to_vec()).or_default())
+    }
+
+    // Returns the cached entry for args if fresh, otherwise runs and caches run's result.
+    // Errors and results rejected by is_cacheable are not cached, the next waiter runs again.
+    // run returns its result and whether it joined another run.
+    pub async fn get_or_run<F, Fut, E>(
+        &self,
+        args: &[String],
+        run: F,
+        is_cacheable: impl FnOnce(&T) -> bool,
+    ) -> Result<CacheResult<T>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(Arc<T>, bool), E>>,
+    {
+        let slot = self.slot(args);
+
+        let wait_time = Instant::now();
+
+        let mut slot_entry = slot.lock().await;
+
+        if let Some(entry) = slot_entry.as_ref() {
+            if self.is_fresh(entry) {
+                // Stored while this call waited for the lock, so it joined that run.
+                return Ok(CacheResult::Stored {
+                    entry: entry.clone(),
+                    shared: entry.stored_time >= wait_time,
+                });
+            }
+        }
+
+        let (value, shared) = run().await?;
+
+        if !is_cacheable(&value) {
+            return Ok(CacheResult::Uncached { value, shared });
+        }
+
+        let entry = CacheEntry {
+            value,
+            stored_time: Instant::now(),
+        };
+
+        *slot_entry = Some(entry.clone());
+
+        Ok(CacheResult::Stored { entry, shared })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(value: &str) -> Vec<String> {
+        vec![value.to_owned()]
+    }
+
+    async fn get(
+        cache: &CommandCache<u32>,
+        args: &[String],
+        value: u32,
+        is_cacheable: bool,
+    ) -> (u32, bool) {
+        let result = cache
+            .get_or_run(
+                args,
+                || async { Ok::<_, ()>((Arc::new(value), false)) },
+                |_| is_cacheable,
+            )
+            .await
+            .unwrap();
+
+        match result {
+            CacheResult::Stored { entry, .. } => (*entry.value, true),
+            CacheResult::Uncached { value, .. } => (*value, false),
+        }
+    }
+
+    #[tokio::test]
+    async fn fresh_entry_is_returned_without_running() {
+        let cache = CommandCache::new(Duration::from_secs(60), 10);
+
+        assert_eq!(get(&cache, &args("a"), 1, true).await, (1, true));
+        assert_eq!(get(&cache, &args("a"), 2, true).await, (1, true));
+        assert_eq!(get(&cache, &args("b"), 3, true).await, (3, true));
+    }
+
+    #[tokio::test]
+    async fn expired_entry_runs_again() {
+        let cache = CommandCache::new(Duration::from_millis(20), 10);
+
+        assert_eq!(get(&cache, &args("a"), 1, true).await, (1, true));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(get(&cache, &args("a"), 2, true).await, (2, true));
+    }
+
+    #[tokio::test]
+    async fn uncacheable_result_is_not_stored() {
+        let cache = CommandCache::new(Duration::from_secs(60), 10);
+
+        assert_eq!(get(&cache, &args("a"), 1, false).await, (1, false));
+        assert_eq!(get(&cache, &args("a"), 2, true).await, (2, true));
+    }
+
+    #[tokio::test]
+    async fn oldest_entry_is_evicted_at_max_entries() {
+        let cache = CommandCache::new(Duration::from_secs(60), 2);
+
+        get(&cache, &args("a"), 1, true).await;
+        get(&cache, &args("b"), 2, true).await;
+        get(&cache, &args("c"), 3, true).await;
+
+        assert_eq!(cache.slots.lock().unwrap().len(), 2);
+        assert_eq!(get(&cache, &args("b"), 4, true).await, (2, true));
+        assert_eq!(get(&cache, &args("a"), 5, true).await, (5, true));
+    }
+
+    #[tokio::test]
+    async fn waiter_during_a_miss_shares_the_run() {
+        let cache = CommandCache::new(Duration::from_secs(60), 10);
+
+        let run = |value: u32| async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok::<_, ()>((Arc::new(value), false))
+        };
+
+        let args = args("a");
+
+        let (leader, waiter) = tokio::join!(
+            cache.get_or_run(&args, || run(1), |_| true),
+            cache.get_or_run(&args, || run(2), |_| true),
+        );
+
+        for (result, expected_shared) in [(leader, false), (waiter, true)] {
+            match result.unwrap() {
+                CacheResult::Stored { entry, shared } => {
+                    assert_eq!(*entry.value, 1);
+                    assert_eq!(shared, expected_shared);
+                }
+                CacheResult::Uncached { .. } => panic!("result was not stored"),
+            }
+        }
+
+        let later = cache.get_or_run(&args, || run(3), |_| true).await;
+        assert!(matches!(
+            later,
+            Ok(CacheResult::Stored { shared: false, .. })
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use async_trait::async_trait;
+
+use serde::{Deserialize, Serialize};
+
+use tokio::task::JoinSet;
+
+use crate::handlers::{
+    utils::{build_json_error_response, build_json_response},
+    {FastCGIRequest, HttpResponse, RequestHandler},
+};
+
+use super::{
+    cache::CacheMode, current_time_string, history, validate_path_segment, RunCommandError,
+    RunCommandHandler, RunCommandResponse,
+};
+
+#[derive(thiserror::Error, Debug)]
+enum DashboardRequestError {
+    #[error("ids parameter or JSON body with ids is required")]
+    MissingIds,
+
+    #[error("invalid JSON body: {0}")]
+    InvalidBody(serde_json::Error),
+
+    #[error("too many ids, at most {0}")]
+    TooManyIds(usize),
+}
+
+#[derive(Debug, Deserialize)]
+struct DashboardRequestBody {
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DashboardCommandResponse {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<RunCommandResponse<'static>>,
+}
+
+#[derive(Debug, Serialize)]
+struct DashboardResponse<'a> {
+    now: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dashboard: Option<&'a str>,
+    results: Vec<DashboardCommandResponse>,
+}
+
+// Runs several commands concurrently and returns all of their results in one response.
+pub struct DashboardHandler {
+    // A disabled command has the reason instead of a handler.
+    run_command_handlers: BTreeMap<&'static str, Result<Arc<RunCommandHandler>, String>>,
+    dashboards: &'static BTreeMap<String, Vec<String>>,
+    dashboard_uri: String,
+}
+
+impl DashboardHandler {
+    pub fn new(
+        run_command_handlers: BTreeMap<&'static str, Result<Arc<RunCommandHandler>, String>>,
+        dashboards: &'static BTreeMap<String, Vec<String>>,
+        dashboard_uri: String,
+    ) -> anyhow::Result<Self> {
+        for (name, ids) in dashboards {
+            validate_path_segment("dashboard name", name)?;
+            for id in ids {
+                anyhow::ensure!(
+                    run_command_handlers.contains_key(id.as_str()),
+                    "dashboard '{}' has unknown command '{}'",
+                    name,
+                    id
+                );
+            }
+        }
+
+        Ok(Self {
+            run_command_handlers,
+            dashboards,
+            dashboard_uri,
+        })
+    }
+
+    // Ids are a comma separated ids query or form parameter, or a JSON body.
+    fn requested_ids(
+        &self,
+        request: &FastCGIRequest<'_>,
+    ) -> Result<Vec<String>, DashboardRequestError> {
+        let ids = match request.query_or_form_param("ids") {
+            Some(ids) => ids
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            None if request
+                .param("content_type")
+                .is_some_and(|content_type| content_type.starts_with("application/json")) =>
+            {
+                serde_json::from_slice::<DashboardRequestBody>(request.body())
+                    .map_err(DashboardRequestError::InvalidBody)?
+                    .ids
+            }
+            None => Vec::new(),
+        };
+
+        let mut unique_ids: Vec<String> = Vec::with_capacity(ids.len());
+        for id in ids {
+            if !unique_ids.contains(&id) {
+                unique_ids.push(id);
+            }
+        }
+
+        if unique_ids.is_empty() {
+            return Err(DashboardRequestError::MissingIds);
+        }
+
+        if unique_ids.len() > self.run_command_handlers.len() {
+            return Err(DashboardRequestError::TooManyIds(
+                self.run_command_handlers.len(),
+            ));
+        }
+
+        Ok(unique_ids)
+    }
+
+    // Runs each command with its default arguments, using its cache or schedule.
+    async fn command_response(
+        run_command_handler: Arc<RunCommandHandler>,
+        caller: String,
+    ) -> Result<RunCommandResponse<'static>, String> {
+        let args = run_command_handler
+            .command_arguments
+            .default_args()
+            .map_err(|err| err.to_string())?;
+
+        let acquire_timeout = run_command_handler.command_semaphores.acquire_timeout(None);
+
+        match run_command_handler
+            .get_result(&args, CacheMode::Use, acquire_timeout, &caller)
+            .await
+        {
+            Ok((command_result, result_source)) => Ok(RunCommandHandler::result_response(
+                &command_result,
+                result_source,
+            )),
+            Err(RunCommandError::Run(command_result)) => Ok(command_result.response),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    async fn run_commands(
+        &self,
+        dashboard: Option<&str>,
+        ids: Vec<String>,
+        caller: String,
+    ) -> HttpResponse {
+        let mut results: Vec<DashboardCommandResponse> = ids
+            .into_iter()
+            .map(|id| DashboardCommandResponse {
+                id,
+                error: None,
+                result: None,
+            })
+            .collect();
+
+        // Dropping the set when the request is aborted stops the runs.
+        let mut join_set = JoinSet::new();
+
+        for (index, command_response) in results.iter_mut().enumerate() {
+            match self.run_command_handlers.get(command_response.id.as_str()) {
+                None => command_response.error = Some("unknown command".to_owned()),
+                Some(Err(reason)) => {
+                    command_response.error = Some(format!("command is disabled: {}", reason))
+                }
+                Some(Ok(run_command_handler)) => {
+                    let run_command_handler = Arc::clone(run_command_handler);
+                    let caller = caller.clone();
+                    join_set.spawn(async move {
+                        (
+                            index,
+                            Self::command_response(run_command_handler, caller).await,
+                        )
+                    });
+                }
+            }
+        }
+
+        while let Some(joined) = join_set.join_next().await {
+            match joined {
+                Ok((index, Ok(response))) => results[index].result = Some(response),
+                Ok((index, Err(error))) => results[index].error = Some(error),
+                Err(err) => {
+                    return build_json_error_response(
+                        http::StatusCode::INTERNAL_SERVER_ERROR,
+                        &format!("dashboard command failed: {}", err),
+                    )
+                }
+            }
+        }
+
+        build_json_response(DashboardResponse {
+            now: current_time_string(),
+            dashboard,
+            results,
+        })
+    }
+}
+
+#[async_trait]
+impl RequestHandler for DashboardHandler {
+    async fn handle(&self, request: FastCGIRequest<'_>) -> HttpResponse {
+        let caller = history::request_caller(&request);
+
+        let request_uri = request.request_uri().unwrap_or_default();
+
+        match request_uri.strip_prefix(self.dashboard_uri.as_str()) {
+            Some("") => match self.requested_ids(&request) {
+                Err(err) => {
+                    build_json_error_response(http::StatusCode::BAD_REQUEST, &err.to_string())
+                }
+                Ok(ids) => self.run_commands(None, ids, caller).await,
+            },
+            Some(dashboard_path) => match dashboard_path
+                .strip_prefix('/')
+                .and_then(|name| self.dashboards.get_key_value(name))
+            {
+                Some((name, ids)) => self.run_commands(Some(name), ids.clone(), caller).await,
+                None => build_json_error_response(http::StatusCode::NOT_FOUND, "unknown dashboard"),
+            },
+            None => build_json_error_response(http::StatusCode::NOT_FOUND, "unknown dashboard"),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use serde::Serialize;
+
+use crate::request::FastCGIRequest;
+
+use super::RunCommandResponse;
+
+// Caller recorded for runs started by a command's schedule.
+pub const SCHEDULE_CALLER: &str = "schedule";
+
+// Describes who started a run: remote_user@remote_addr when the web server supplies them.
+pub fn request_caller(request: &FastCGIRequest<'_>) -> String {
+    match (request.param("remote_user"), request.param("remote_addr")) {
+        (Some(remote_user), Some(remote_addr)) if !remote_user.is_empty() => {
+            format!("{}@{}", remote_user, remote_addr)
+        }
+        (_, Some(remote_addr)) => remote_addr.to_owned(),
+        (_, None) => "unknown".to_owned(),
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HistoryEntry {
+    pub time: String,
+    pub duration_ms: u128,
+    pub caller: String,
+    pub args: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub timed_out: bool,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+}
+
+// Bounded record of a command's most recent runs, oldest dropped first.
+pub struct CommandHistory {
+    max_entries: usize,
+    include_output: bool,
+    entries: Mutex<VecDeque<HistoryEntry>>,
+}
+
+impl CommandHistory {
+    pub fn new(max_entries: usize, include_output: bool) -> Self {
+        Self {
+            max_entries,
+            include_output,
+            entries: Mutex::new(VecDeque::with_capacity(max_entries)),
+        }
+    }
+
+    pub fn record(&self, caller: &str, response: &RunCommandResponse<'_>) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let entry = HistoryEntry {
+            time: response.now.clone(),
+            duration_ms: response.command_duration_ms,
+            caller: caller.to_owned(),
+            args: response.args.clone(),
+            error: response.error.clone(),
+            timed_out: response.timed_out,
+            success: response.success,
+            exit_code: response.exit_code,
+            signal: response.signal,
+            stdout: self.include_output.then(|| response.stdout.clone()),
+            stderr: self.include_output.then(|| response.stderr.clone()),
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.max_entries {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    // Entries newest first.
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
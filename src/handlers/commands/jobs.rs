@@ -0,0 +1,446 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+
+use log::info;
+
+use serde::Serialize;
+
+use tokio::time::{Duration, Instant};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::handlers::{
+    utils::{build_json_error_response, build_json_response, build_json_status_response},
+    {FastCGIRequest, HttpResponse, RequestHandler},
+};
+
+use super::{
+    current_time_string, history,
+    runner::{OutputObserver, OutputStream, RunHooks},
+    CommandResult, RunCommandError, RunCommandHandler, RunCommandResponse,
+};
+
+#[derive(thiserror::Error, Debug)]
+#[error("too many jobs, at most {0} running or retained")]
+pub struct TooManyJobsError(usize);
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobStatus {
+    Running,
+    Cancelling,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Default)]
+struct JobOutput {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+struct JobOutcome {
+    result: Result<CommandResult, String>,
+    finished_time: Instant,
+}
+
+// A command run in the background.  Output is collected as it is read so it can be
+// polled before the command finishes.
+pub struct Job {
+    id: u64,
+    command_id: &'static str,
+    args: Vec<String>,
+    stdin: Vec<u8>,
+    caller: String,
+    start_time: String,
+    cancellation_token: CancellationToken,
+    output: Mutex<JobOutput>,
+    outcome: Mutex<Option<JobOutcome>>,
+}
+
+impl Job {
+    fn finish(&self, result: Result<CommandResult, String>) {
+        *self.outcome.lock().unwrap() = Some(JobOutcome {
+            result,
+            finished_time: Instant::now(),
+        });
+
+        // The result holds the complete output from here on.
+        *self.output.lock().unwrap() = JobOutput::default();
+    }
+
+    fn is_finished(&self) -> bool {
+        self.outcome.lock().unwrap().is_some()
+    }
+
+    fn status(&self, outcome: Option<&JobOutcome>) -> JobStatus {
+        match outcome.map(|outcome| &outcome.result) {
+            None if self.cancellation_token.is_cancelled() => JobStatus::Cancelling,
+            None => JobStatus::Running,
+            Some(Err(_)) => JobStatus::Failed,
+            Some(Ok(command_result)) if command_result.response.error.is_some() => {
+                JobStatus::Failed
+            }
+            Some(Ok(command_result)) if command_result.response.cancelled => JobStatus::Cancelled,
+            Some(Ok(_)) => JobStatus::Finished,
+        }
+    }
+
+    fn is_expired(&self, retention: Duration) -> bool {
+        self.outcome
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|outcome| outcome.finished_time.elapsed() >= retention)
+    }
+}
+
+impl OutputObserver for Job {
+    fn output(&self, stream: OutputStream, data: &[u8]) {
+        let mut output = self.output.lock().unwrap();
+        match stream {
+            OutputStream::Stdout => output.stdout.extend_from_slice(data),
+            OutputStream::Stderr => output.stderr.extend_from_slice(data),
+        }
+    }
+}
+
+// Jobs of all commands.  Finished jobs are kept for the retention period, and the
+// number of running and retained jobs is bounded.
+pub struct JobStore {
+    max_jobs: usize,
+    retention: Duration,
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+}
+
+impl JobStore {
+    pub fn new(max_jobs: usize, retention: Duration) -> Self {
+        Self {
+            max_jobs,
+            retention,
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn remove_expired(&self, jobs: &mut BTreeMap<u64, Arc<Job>>) {
+        jobs.retain(|_, job| !job.is_expired(self.retention));
+    }
+
+    fn create(
+        &self,
+        command_id: &'static str,
+        args: Vec<String>,
+        stdin: Vec<u8>,
+        caller: String,
+    ) -> Result<Arc<Job>, TooManyJobsError> {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        self.remove_expired(&mut jobs);
+
+        if jobs.len() >= self.max_jobs {
+            return Err(TooManyJobsError(self.max_jobs));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let job = Arc::new(Job {
+            id,
+            command_id,
+            args,
+            stdin,
+            caller,
+            start_time: current_time_string(),
+            cancellation_token: CancellationToken::new(),
+            output: Mutex::new(JobOutput::default()),
+            outcome: Mutex::new(None),
+        });
+
+        jobs.insert(id, Arc::clone(&job));
+
+        Ok(job)
+    }
+
+    fn get(&self, command_id: &str, id: u64) -> Option<Arc<Job>> {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        self.remove_expired(&mut jobs);
+
+        jobs.get(&id)
+            .filter(|job| job.command_id == command_id)
+            .cloned()
+    }
+
+    fn remove(&self, id: u64) {
+        self.jobs.lock().unwrap().remove(&id);
+    }
+
+    fn command_jobs(&self, command_id: &str) -> Vec<Arc<Job>> {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        self.remove_expired(&mut jobs);
+
+        jobs.values()
+            .filter(|job| job.command_id == command_id)
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JobStartedResponse<'a> {
+    job_id: u64,
+    command_id: &'a str,
+    uri: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct JobSummaryResponse<'a> {
+    job_id: u64,
+    args: &'a [String],
+    caller: &'a str,
+    start_time: &'a str,
+    status: JobStatus,
+}
+
+#[derive(Debug, Serialize)]
+struct JobResponse<'a> {
+    #[serde(flatten)]
+    summary: JobSummaryResponse<'a>,
+    command_id: &'a str,
+    // Output so far, while the job is running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'a RunCommandResponse<'static>>,
+}
+
+fn build_method_not_allowed_response(allow: &'static str) -> HttpResponse {
+    let mut http_response =
+        build_json_error_response(http::StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+
+    http_response
+        .headers_mut()
+        .insert(http::header::ALLOW, http::HeaderValue::from_static(allow));
+
+    http_response
+}
+
+// Serves commands/{id}/jobs and commands/{id}/jobs/{job_id}.
+pub struct CommandJobsHandler {
+    run_command_handler: Arc<RunCommandHandler>,
+    job_store: Arc<JobStore>,
+    jobs_uri: String,
+}
+
+impl CommandJobsHandler {
+    pub fn new(
+        run_command_handler: Arc<RunCommandHandler>,
+        job_store: Arc<JobStore>,
+        jobs_uri: String,
+    ) -> Self {
+        Self {
+            run_command_handler,
+            job_store,
+            jobs_uri,
+        }
+    }
+
+    fn command_id(&self) -> &'static str {
+        self.run_command_handler.command_info.id()
+    }
+
+    fn start_job(&self, request: &FastCGIRequest<'_>) -> HttpResponse {
+        let args = match self.run_command_handler.command_arguments.build(request) {
+            Err(err) => {
+                return build_json_error_response(http::StatusCode::BAD_REQUEST, &err.to_string())
+            }
+            Ok(args) => args,
+        };
+
+        let stdin = match self.run_command_handler.command_stdin(request) {
+            Err(err) => {
+                return build_json_error_response(
+                    http::StatusCode::PAYLOAD_TOO_LARGE,
+                    &err.to_string(),
+                )
+            }
+            Ok(stdin) => stdin.to_vec(),
+        };
+
+        let job = match self.job_store.create(
+            self.command_id(),
+            args,
+            stdin,
+            history::request_caller(request),
+        ) {
+            Err(err) => {
+                return build_json_error_response(
+                    http::StatusCode::TOO_MANY_REQUESTS,
+                    &err.to_string(),
+                )
+            }
+            Ok(job) => job,
+        };
+
+        info!("command '{}' started job {}", self.command_id(), job.id);
+
+        let run_command_handler = Arc::clone(&self.run_command_handler);
+        let task_job = Arc::clone(&job);
+
+        tokio::spawn(async move {
+            let job = task_job;
+
+            let acquire_timeout = run_command_handler.command_semaphores.acquire_timeout(None);
+
+            let result = run_command_handler
+                .run_command(
+                    &job.args,
+                    &job.stdin,
+                    acquire_timeout,
+                    &job.caller,
+                    RunHooks {
+                        output_observer: Some(job.as_ref()),
+                        cancellation_token: Some(&job.cancellation_token),
+                    },
+                )
+                .await;
+
+            job.finish(match result {
+                Ok(command_result) => Ok(command_result),
+                Err(RunCommandError::Run(command_result)) => Ok(*command_result),
+                Err(err) => Err(err.to_string()),
+            });
+        });
+
+        let uri = format!("{}/{}", self.jobs_uri, job.id);
+
+        let mut http_response = build_json_status_response(
+            http::StatusCode::ACCEPTED,
+            JobStartedResponse {
+                job_id: job.id,
+                command_id: self.command_id(),
+                uri: &uri,
+            },
+        );
+
+        if let Ok(location) = http::HeaderValue::from_str(&uri) {
+            http_response
+                .headers_mut()
+                .insert(http::header::LOCATION, location);
+        }
+
+        http_response
+    }
+
+    fn build_job_summary<'a>(&self, job: &'a Job) -> JobSummaryResponse<'a> {
+        let outcome = job.outcome.lock().unwrap();
+
+        JobSummaryResponse {
+            job_id: job.id,
+            args: &job.args,
+            caller: &job.caller,
+            start_time: &job.start_time,
+            status: job.status(outcome.as_ref()),
+        }
+    }
+
+    fn build_job_response(&self, job: &Job, status_code: http::StatusCode) -> HttpResponse {
+        let summary = self.build_job_summary(job);
+
+        let outcome = job.outcome.lock().unwrap();
+
+        let mut response = JobResponse {
+            summary,
+            command_id: job.command_id,
+            stdout: None,
+            stderr: None,
+            error: None,
+            result: None,
+        };
+
+        match outcome.as_ref().map(|outcome| &outcome.result) {
+            None => {
+                let output = job.output.lock().unwrap();
+                let redactor = &self.run_command_handler.redactor;
+                response.stdout = Some(redactor.redact(&String::from_utf8_lossy(&output.stdout)).0);
+                response.stderr = Some(redactor.redact(&String::from_utf8_lossy(&output.stderr)).0);
+            }
+            Some(Err(error)) => response.error = Some(error),
+            Some(Ok(command_result)) => response.result = Some(&command_result.response),
+        }
+
+        build_json_status_response(status_code, response)
+    }
+
+    fn handle_jobs(&self, request_method: &str, request: &FastCGIRequest<'_>) -> HttpResponse {
+        match request_method {
+            "POST" => self.start_job(request),
+            "GET" => {
+                let jobs = self.job_store.command_jobs(self.command_id());
+                let summaries: Vec<JobSummaryResponse<'_>> =
+                    jobs.iter().map(|job| self.build_job_summary(job)).collect();
+                build_json_response(summaries)
+            }
+            _ => build_method_not_allowed_response("GET, POST"),
+        }
+    }
+
+    fn handle_job(&self, request_method: &str, job_id: &str) -> HttpResponse {
+        let job = match job_id
+            .parse()
+            .ok()
+            .and_then(|job_id| self.job_store.get(self.command_id(), job_id))
+        {
+            None => return build_json_error_response(http::StatusCode::NOT_FOUND, "unknown job"),
+            Some(job) => job,
+        };
+
+        match request_method {
+            "GET" => self.build_job_response(&job, http::StatusCode::OK),
+            "DELETE" => {
+                if job.is_finished() {
+                    // Finished jobs are removed instead.
+                    self.job_store.remove(job.id);
+                    self.build_job_response(&job, http::StatusCode::OK)
+                } else {
+                    info!("command '{}' cancelling job {}", self.command_id(), job.id);
+                    job.cancellation_token.cancel();
+                    self.build_job_response(&job, http::StatusCode::ACCEPTED)
+                }
+            }
+            _ => build_method_not_allowed_response("GET, DELETE"),
+        }
+    }
+}
+
+#[async_trait]
+impl RequestHandler for CommandJobsHandler {
+    async fn handle(&self, request: FastCGIRequest<'_>) -> HttpResponse {
+        let request_method = request.param("request_method").unwrap_or_default();
+
+        let request_uri = request.request_uri().unwrap_or_default();
+
+        match request_uri.strip_prefix(self.jobs_uri.as_str()) {
+            Some("") => self.handle_jobs(request_method, &request),
+            Some(job_path) => match job_path.strip_prefix('/') {
+                Some(job_id) => self.handle_job(request_method, job_id),
+                None => build_json_error_response(http::StatusCode::NOT_FOUND, "unknown job"),
+            },
+            None => build_json_error_response(http::StatusCode::NOT_FOUND, "unknown job"),
+        }
+    }
+}
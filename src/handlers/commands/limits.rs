@@ -0,0 +1,117 @@
+use crate::config::{IoNiceClass, ResourceLimits};
+
+use super::sandbox::probe;
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+// Validated copy of ResourceLimits that can be applied in the child after fork.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChildResourceLimits {
+    nice: Option<libc::c_int>,
+    ioprio: Option<libc::c_int>,
+    cpu_time_seconds: Option<u64>,
+    address_space_bytes: Option<u64>,
+    open_files: Option<u64>,
+    processes: Option<u64>,
+}
+
+impl ChildResourceLimits {
+    pub fn new(resource_limits: &ResourceLimits) -> anyhow::Result<Self> {
+        if let Some(nice) = resource_limits.nice() {
+            anyhow::ensure!(
+                (-20..=19).contains(nice),
+                "nice {} must be between -20 and 19",
+                nice
+            );
+        }
+
+        let ioprio = match resource_limits.ionice() {
+            None => None,
+            Some(ionice) => {
+                let class = match ionice.class() {
+                    IoNiceClass::Realtime => 1,
+                    IoNiceClass::BestEffort => 2,
+                    IoNiceClass::Idle => 3,
+                };
+                let level = ionice.level().unwrap_or(4);
+                anyhow::ensure!(level <= 7, "ionice level {} must be between 0 and 7", level);
+                Some((class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level))
+            }
+        };
+
+        let child_resource_limits = Self {
+            nice: *resource_limits.nice(),
+            ioprio,
+            cpu_time_seconds: *resource_limits.cpu_time_seconds(),
+            address_space_bytes: *resource_limits.address_space_bytes(),
+            open_files: *resource_limits.open_files(),
+            processes: *resource_limits.processes(),
+        };
+
+        // A negative nice, the realtime ionice class or raising a hard rlimit needs privileges.
+        if !child_resource_limits.is_empty() {
+            probe("nice, ionice or rlimit setting", move || {
+                child_resource_limits.apply()
+            })?;
+        }
+
+        Ok(child_resource_limits)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nice.is_none()
+            && self.ioprio.is_none()
+            && self.cpu_time_seconds.is_none()
+            && self.address_space_bytes.is_none()
+            && self.open_files.is_none()
+            && self.processes.is_none()
+    }
+
+    // Called in the child between fork and exec, so only async-signal-safe calls are allowed.
+    pub fn apply(&self) -> Result<(), std::io::Error> {
+        if let Some(nice) = self.nice {
+            // Safety: setpriority has no memory safety requirements.
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        if let Some(ioprio) = self.ioprio {
+            // Safety: ioprio_set has no memory safety requirements.
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        set_rlimit(libc::RLIMIT_CPU, self.cpu_time_seconds)?;
+        set_rlimit(libc::RLIMIT_AS, self.address_space_bytes)?;
+        set_rlimit(libc::RLIMIT_NOFILE, self.open_files)?;
+        // RLIMIT_NPROC counts all processes of the user, not only those started by the command.
+        set_rlimit(libc::RLIMIT_NPROC, self.processes)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type RlimitResource = libc::c_int;
+
+fn set_rlimit(resource: RlimitResource, limit: Option<u64>) -> Result<(), std::io::Error> {
+    if let Some(limit) = limit {
+        let rlimit = libc::rlimit {
+            rlim_cur: limit as libc::rlim_t,
+            rlim_max: limit as libc::rlim_t,
+        };
+
+        // Safety: rlimit is a valid pointer for the duration of the call.
+        if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,385 @@
+use anyhow::Context;
+
+use regex::Regex;
+
+use serde_json::{Map, Value};
+
+use crate::config::OutputFormat;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ParseOutputError {
+    #[error("stdout is not valid JSON: {0}")]
+    Json(serde_json::Error),
+
+    #[error("stdout line {line_number} is not valid JSON: {err}")]
+    JsonLine {
+        line_number: usize,
+        err: serde_json::Error,
+    },
+}
+
+// Splits line into at most max_columns fields, the last field taking the rest of the line.
+// A max_columns of 0 means no limit.
+fn split_columns<'a>(line: &'a str, delimiter: Option<&str>, max_columns: usize) -> Vec<&'a str> {
+    if let Some(delimiter) = delimiter {
+        let fields = if max_columns == 0 {
+            line.split(delimiter).collect::<Vec<_>>()
+        } else {
+            line.splitn(max_columns, delimiter).collect()
+        };
+        return fields.into_iter().map(str::trim).collect();
+    }
+
+    let mut fields = Vec::new();
+    let mut rest = line.trim();
+
+    while !rest.is_empty() {
+        if fields.len() + 1 == max_columns {
+            fields.push(rest);
+            break;
+        }
+        match rest.find(char::is_whitespace) {
+            None => {
+                fields.push(rest);
+                break;
+            }
+            Some(index) => {
+                fields.push(&rest[..index]);
+                rest = rest[index..].trim_start();
+            }
+        }
+    }
+
+    fields
+}
+
+// Turns stdout into JSON according to the command's output_format.
+pub enum OutputParser {
+    Text,
+    Json,
+    JsonLines,
+    Columns {
+        skip_lines: usize,
+        header: bool,
+        last_header_has_spaces: bool,
+        columns: &'static [String],
+        delimiter: Option<&'static str>,
+    },
+    Regex {
+        regex: Regex,
+    },
+    KeyValue {
+        separator: &'static str,
+    },
+}
+
+impl OutputParser {
+    pub fn new(output_format: &'static OutputFormat) -> anyhow::Result<Self> {
+        Ok(match output_format {
+            OutputFormat::Text => Self::Text,
+            OutputFormat::Json => Self::Json,
+            OutputFormat::JsonLines => Self::JsonLines,
+            OutputFormat::Columns {
+                skip_lines,
+                header,
+                last_header_has_spaces,
+                columns,
+                delimiter,
+            } => {
+                anyhow::ensure!(
+                    *header || !columns.is_empty(),
+                    "columns output_format needs a header line or columns"
+                );
+                if let Some(delimiter) = delimiter {
+                    anyhow::ensure!(!delimiter.is_empty(), "delimiter must not be empty");
+                }
+                Self::Columns {
+                    skip_lines: *skip_lines,
+                    header: *header,
+                    last_header_has_spaces: *last_header_has_spaces,
+                    columns,
+                    delimiter: delimiter.as_deref(),
+                }
+            }
+            OutputFormat::Regex { pattern } => {
+                let regex = Regex::new(pattern)
+                    .with_context(|| format!("invalid output_format pattern '{}'", pattern))?;
+                anyhow::ensure!(
+                    regex.capture_names().flatten().next().is_some(),
+                    "output_format pattern '{}' has no named capture groups",
+                    pattern
+                );
+                Self::Regex { regex }
+            }
+            OutputFormat::KeyValue { separator } => {
+                anyhow::ensure!(!separator.is_empty(), "separator must not be empty");
+                Self::KeyValue { separator }
+            }
+        })
+    }
+
+    // Returns None for text output.
+    pub fn parse(&self, stdout: &str) -> Result<Option<Value>, ParseOutputError> {
+        match self {
+            Self::Text => Ok(None),
+            Self::Json => serde_json::from_str(stdout)
+                .map(Some)
+                .map_err(ParseOutputError::Json),
+            Self::JsonLines => stdout
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(index, line)| {
+                    serde_json::from_str(line).map_err(|err| ParseOutputError::JsonLine {
+                        line_number: index + 1,
+                        err,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(|values| Some(Value::Array(values))),
+            Self::Columns {
+                skip_lines,
+                header,
+                last_header_has_spaces,
+                columns,
+                delimiter,
+            } => Ok(Some(Self::parse_columns(
+                stdout,
+                *skip_lines,
+                *header,
+                *last_header_has_spaces,
+                columns,
+                *delimiter,
+            ))),
+            Self::Regex { regex } => Ok(Some(Self::parse_regex(stdout, regex))),
+            Self::KeyValue { separator } => Ok(Some(Self::parse_key_value(stdout, separator))),
+        }
+    }
+
+    fn parse_columns(
+        stdout: &str,
+        skip_lines: usize,
+        header: bool,
+        last_header_has_spaces: bool,
+        columns: &[String],
+        delimiter: Option<&str>,
+    ) -> Value {
+        let mut lines = stdout
+            .lines()
+            .skip(skip_lines)
+            .filter(|line| !line.trim().is_empty());
+
+        let header_line = if header { lines.next() } else { None };
+
+        let lines: Vec<&str> = lines.collect();
+
+        let header_columns = match header_line {
+            None => Vec::new(),
+            Some(header_line) if last_header_has_spaces => {
+                // Rows can not tell a last header name with spaces from a missing last value,
+                // so the widest row decides how many names the header has.
+                let max_fields = lines
+                    .iter()
+                    .map(|line| split_columns(line, delimiter, 0).len())
+                    .max()
+                    .unwrap_or(0);
+                split_columns(header_line, delimiter, max_fields)
+            }
+            Some(header_line) => split_columns(header_line, delimiter, 0),
+        };
+
+        // Configured column names take precedence over the header line.
+        let names: Vec<&str> = if columns.is_empty() {
+            header_columns
+        } else {
+            columns.iter().map(String::as_str).collect()
+        };
+
+        Value::Array(
+            lines
+                .into_iter()
+                .map(|line| {
+                    names
+                        .iter()
+                        .zip(split_columns(line, delimiter, names.len()))
+                        .map(|(name, value)| ((*name).to_owned(), Value::from(value)))
+                        .collect::<Map<_, _>>()
+                        .into()
+                })
+                .collect(),
+        )
+    }
+
+    fn parse_regex(stdout: &str, regex: &Regex) -> Value {
+        Value::Array(
+            stdout
+                .lines()
+                .filter_map(|line| regex.captures(line))
+                .map(|captures| {
+                    regex
+                        .capture_names()
+                        .flatten()
+                        .filter_map(|name| {
+                            captures
+                                .name(name)
+                                .map(|value| (name.to_owned(), Value::from(value.as_str())))
+                        })
+                        .collect::<Map<_, _>>()
+                        .into()
+                })
+                .collect(),
+        )
+    }
+
+    fn parse_key_value(stdout: &str, separator: &str) -> Value {
+        let mut rows = Vec::new();
+        let mut row = Map::new();
+
+        for line in stdout.lines() {
+            if line.trim().is_empty() {
+                if !row.is_empty() {
+                    rows.push(Value::Object(std::mem::take(&mut row)));
+                }
+                continue;
+            }
+            if let Some((key, value)) = line.split_once(separator) {
+                row.insert(key.trim().to_owned(), Value::from(value.trim()));
+            }
+        }
+
+        if !row.is_empty() {
+            rows.push(Value::Object(row));
+        }
+
+        Value::Array(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn split_columns_on_whitespace() {
+        assert_eq!(split_columns("  a  b\tc ", None, 0), vec!["a", "b", "c"]);
+        assert_eq!(split_columns("a b c d", None, 2), vec!["a", "b c d"]);
+        assert!(split_columns("   ", None, 0).is_empty());
+    }
+
+    #[test]
+    fn split_columns_on_delimiter() {
+        assert_eq!(
+            split_columns("a, b,,c", Some(","), 0),
+            vec!["a", "b", "", "c"]
+        );
+        assert_eq!(split_columns("a:b:c", Some(":"), 2), vec!["a", "b:c"]);
+    }
+
+    #[test]
+    fn parse_columns_keeps_last_header_name_with_spaces() {
+        let stdout = "Filesystem      Size  Used Avail Use% Mounted on\n\
+                      /dev/vda        252G   19G   78G  20% /\n\
+                      tmpfs           1.9G     0  1.9G   0% /dev/shm\n";
+
+        assert_eq!(
+            OutputParser::parse_columns(stdout, 0, true, true, &[], None),
+            json!([
+                {
+                    "Filesystem": "/dev/vda",
+                    "Size": "252G",
+                    "Used": "19G",
+                    "Avail": "78G",
+                    "Use%": "20%",
+                    "Mounted on": "/",
+                },
+                {
+                    "Filesystem": "tmpfs",
+                    "Size": "1.9G",
+                    "Used": "0",
+                    "Avail": "1.9G",
+                    "Use%": "0%",
+                    "Mounted on": "/dev/shm",
+                },
+            ])
+        );
+
+        // Without the flag rows missing the last value, as lsblk prints for unmounted
+        // disks, keep every header name.
+        let stdout = "NAME TYPE MOUNTPOINTS\nsda  disk\nsdb  disk\n";
+
+        assert_eq!(
+            OutputParser::parse_columns(stdout, 0, true, false, &[], None),
+            json!([{"NAME": "sda", "TYPE": "disk"}, {"NAME": "sdb", "TYPE": "disk"}])
+        );
+    }
+
+    #[test]
+    fn parse_columns_last_column_takes_rest_of_line() {
+        let stdout = "USER PID COMMAND\nroot 1 /sbin/init splash\n";
+
+        assert_eq!(
+            OutputParser::parse_columns(stdout, 0, true, false, &[], None),
+            json!([{"USER": "root", "PID": "1", "COMMAND": "/sbin/init splash"}])
+        );
+    }
+
+    #[test]
+    fn parse_columns_with_configured_names() {
+        let columns = ["name".to_owned(), "value".to_owned()];
+        let stdout = "skipped\n\na=1\nb=2\n";
+
+        assert_eq!(
+            OutputParser::parse_columns(stdout, 1, false, false, &columns, Some("=")),
+            json!([{"name": "a", "value": "1"}, {"name": "b", "value": "2"}])
+        );
+
+        // The header line is skipped but the configured names are used.
+        assert_eq!(
+            OutputParser::parse_columns("k v\nx y\n", 0, true, false, &columns, None),
+            json!([{"name": "x", "value": "y"}])
+        );
+    }
+
+    #[test]
+    fn parse_key_value_blocks() {
+        let stdout = "a=1\nb = 2\nignored\n\n\nc=3=4\n";
+
+        assert_eq!(
+            OutputParser::parse_key_value(stdout, "="),
+            json!([{"a": "1", "b": "2"}, {"c": "3=4"}])
+        );
+        assert_eq!(OutputParser::parse_key_value("\n\n", "="), json!([]));
+    }
+
+    #[test]
+    fn parse_regex_named_groups() {
+        let regex = Regex::new(r"(?P<user>\w+) (?P<pid>\d+)").unwrap();
+
+        assert_eq!(
+            OutputParser::parse_regex("root 1\n-\nwww 22\n", &regex),
+            json!([{"user": "root", "pid": "1"}, {"user": "www", "pid": "22"}])
+        );
+    }
+
+    #[test]
+    fn parse_json_lines_reports_line_number() {
+        let parser = OutputParser::JsonLines;
+
+        assert_eq!(
+            parser.parse("{\"a\": 1}\n\n[2]\n").unwrap(),
+            Some(json!([{"a": 1}, [2]]))
+        );
+
+        match parser.parse("1\n\n{\n") {
+            Err(ParseOutputError::JsonLine { line_number, .. }) => assert_eq!(line_number, 3),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parse_text_is_none() {
+        assert_eq!(OutputParser::Text.parse("anything").unwrap(), None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::Context;
+
+use crate::config::{CommandInfo, CommandStdin};
+
+use super::{limits::ChildResourceLimits, sandbox::Sandbox};
+
+// Environment, working directory, stdin, resource limits and sandbox for a command's child process.
+pub struct ProcessOptions {
+    command_info: &'static CommandInfo,
+    resource_limits: ChildResourceLimits,
+    sandbox: Sandbox,
+}
+
+impl ProcessOptions {
+    pub fn new(command_info: &'static CommandInfo) -> anyhow::Result<Self> {
+        for (key, value) in command_info.env() {
+            anyhow::ensure!(
+                !key.is_empty() && !key.contains(['=', '\0']),
+                "invalid env variable name '{}'",
+                key,
+            );
+
+            if let Some(value) = value {
+                anyhow::ensure!(
+                    !value.contains('\0'),
+                    "env variable '{}' value contains NUL",
+                    key,
+                );
+            }
+        }
+
+        if let Some(working_directory) = command_info.working_directory() {
+            let metadata = std::fs::metadata(working_directory).with_context(|| {
+                format!("working_directory '{}' error", working_directory.display())
+            })?;
+
+            anyhow::ensure!(
+                metadata.is_dir(),
+                "working_directory '{}' is not a directory",
+                working_directory.display(),
+            );
+        }
+
+        if let CommandStdin::File(path) = command_info.stdin() {
+            std::fs::File::open(path)
+                .with_context(|| format!("stdin file '{}' error", path.display()))?;
+        }
+
+        let resource_limits = ChildResourceLimits::new(command_info.resource_limits())
+            .context("resource_limits error")?;
+
+        let sandbox = Sandbox::new(command_info.sandbox()).context("sandbox error")?;
+
+        Ok(Self {
+            command_info,
+            resource_limits,
+            sandbox,
+        })
+    }
+
+    // The PATH used to find commands without a slash, the command's own when it sets one.
+    fn search_path(&self) -> Option<std::ffi::OsString> {
+        match self.command_info.env().get("PATH") {
+            Some(Some(path)) => Some(path.into()),
+            Some(None) => None,
+            None if *self.command_info.env_clear() => None,
+            None => std::env::var_os("PATH"),
+        }
+    }
+
+    // Resolves command the way it will be spawned and checks it is an executable file.
+    pub fn check_executable(&self, command: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(!command.is_empty(), "command must not be empty");
+
+        let is_executable = |path: &Path| {
+            std::fs::metadata(path).is_ok_and(|metadata| {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            })
+        };
+
+        if command.contains('/') {
+            let path = match self.command_info.working_directory() {
+                Some(working_directory) => working_directory.join(command),
+                None => PathBuf::from(command),
+            };
+
+            std::fs::metadata(&path)
+                .with_context(|| format!("command '{}' error", path.display()))?;
+
+            anyhow::ensure!(
+                is_executable(&path),
+                "command '{}' is not an executable file",
+                path.display(),
+            );
+        } else {
+            let search_path = self.search_path().unwrap_or_default();
+
+            anyhow::ensure!(
+                std::env::split_paths(&search_path)
+                    .any(|directory| is_executable(&directory.join(command))),
+                "command '{}' not found in PATH",
+                command,
+            );
+        }
+
+        Ok(())
+    }
+
+    pub async fn configure(
+        &self,
+        command: &mut std::process::Command,
+    ) -> Result<(), std::io::Error> {
+        if *self.command_info.env_clear() {
+            command.env_clear();
+        }
+
+        for (key, value) in self.command_info.env() {
+            match value {
+                Some(value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+
+        if let Some(working_directory) = self.command_info.working_directory() {
+            command.current_dir(working_directory);
+        }
+
+        command.stdin(match self.command_info.stdin() {
+            CommandStdin::Null => Stdio::null(),
+            CommandStdin::Inherit => Stdio::inherit(),
+            CommandStdin::File(path) => {
+                let file = tokio::fs::File::open(path).await?;
+                Stdio::from(file.into_std().await)
+            }
+            // The runner writes the request body to the pipe.
+            CommandStdin::RequestBody => Stdio::piped(),
+        });
+
+        if !self.resource_limits.is_empty() {
+            let resource_limits = self.resource_limits;
+            // Safety: ChildResourceLimits::apply only makes async-signal-safe calls.
+            unsafe {
+                command.pre_exec(move || resource_limits.apply());
+            }
+        }
+
+        self.sandbox.configure(command);
+
+        Ok(())
+    }
+}
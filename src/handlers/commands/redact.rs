@@ -0,0 +1,118 @@
+use anyhow::Context;
+
+use regex::{Captures, Regex};
+
+use crate::config::RedactionRule;
+
+struct CompiledRedactionRule {
+    regex: Regex,
+    replacement: &'static str,
+}
+
+// Replaces matches of the global and command redaction rules in command output.
+pub struct Redactor {
+    rules: Vec<CompiledRedactionRule>,
+}
+
+impl Redactor {
+    pub fn new(rules: impl IntoIterator<Item = &'static RedactionRule>) -> anyhow::Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let regex = Regex::new(rule.pattern())
+                    .with_context(|| format!("invalid redaction pattern '{}'", rule.pattern()))?;
+                Ok(CompiledRedactionRule {
+                    regex,
+                    replacement: rule.replacement(),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Applies the rules in order, returning the redacted text and the number of replacements.
+    pub fn redact(&self, text: &str) -> (String, usize) {
+        let mut text = text.to_owned();
+        let mut replacements = 0;
+
+        for rule in &self.rules {
+            let redacted = rule.regex.replace_all(&text, |captures: &Captures<'_>| {
+                replacements += 1;
+                let mut replacement = String::new();
+                captures.expand(rule.replacement, &mut replacement);
+                replacement
+            });
+            text = redacted.into_owned();
+        }
+
+        (text, replacements)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(rules: serde_json::Value) -> anyhow::Result<Redactor> {
+        let rules: &'static Vec<RedactionRule> = crate::config::leak_test_fixture(rules);
+        Redactor::new(rules)
+    }
+
+    #[test]
+    fn counts_replacements_of_all_rules() {
+        let redactor = redactor(serde_json::json!([
+            {"pattern": r"\d+\.\d+\.\d+\.\d+"},
+            {"pattern": "secret"},
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            redactor.redact("10.0.0.1 and 10.0.0.2 share a secret"),
+            ("[REDACTED] and [REDACTED] share a [REDACTED]".to_owned(), 3)
+        );
+        assert_eq!(
+            redactor.redact("nothing here"),
+            ("nothing here".to_owned(), 0)
+        );
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        // The second rule sees the output of the first.
+        let redactor = redactor(serde_json::json!([
+            {"pattern": "a", "replacement": "b"},
+            {"pattern": "b", "replacement": "c"},
+        ]))
+        .unwrap();
+
+        assert_eq!(redactor.redact("ab"), ("cc".to_owned(), 3));
+    }
+
+    #[test]
+    fn replacement_expands_capture_groups() {
+        let redactor = redactor(serde_json::json!([
+            {"pattern": r"(?P<key>token)=\S+", "replacement": "$key=***"},
+            {"pattern": r"(\w+)@example\.com", "replacement": "${1}@***"},
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            redactor.redact("token=abc mail bob@example.com"),
+            ("token=*** mail bob@***".to_owned(), 2)
+        );
+    }
+
+    #[test]
+    fn empty_and_invalid_rules() {
+        let empty = redactor(serde_json::json!([])).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.redact("text"), ("text".to_owned(), 0));
+
+        assert!(redactor(serde_json::json!([{"pattern": "("}])).is_err());
+    }
+}
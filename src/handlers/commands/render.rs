@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+
+use crate::{config::CommandInfo, handlers::utils::html_escape};
+
+use super::{history::HistoryEntry, RunCommandResponse};
+
+fn command_uri(command_info: &CommandInfo) -> String {
+    let context_configuration = crate::config::instance().context_configuration();
+
+    format!(
+        "{}/commands/{}",
+        context_configuration.context(),
+        command_info.id()
+    )
+}
+
+fn html_page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+        html_escape(title),
+        body,
+    )
+}
+
+pub fn all_commands_text(
+    commands: &[CommandInfo],
+    disabled_reasons: &BTreeMap<&str, String>,
+) -> String {
+    let mut text = String::new();
+
+    for command_info in commands {
+        text.push_str(&format!(
+            "{}\t{}",
+            command_info.id(),
+            command_info.description()
+        ));
+        if let Some(reason) = disabled_reasons.get(command_info.id().as_str()) {
+            text.push_str(&format!("\t(disabled: {})", reason));
+        }
+        text.push('\n');
+    }
+
+    text
+}
+
+pub fn all_commands_html(
+    commands: &[CommandInfo],
+    disabled_reasons: &BTreeMap<&str, String>,
+) -> String {
+    let mut body = String::from("<h2>Commands</h2>\n<ul>\n");
+
+    for command_info in commands {
+        match disabled_reasons.get(command_info.id().as_str()) {
+            None => body.push_str(&format!(
+                "<li><a href=\"{}\">{}</a></li>\n",
+                html_escape(&command_uri(command_info)),
+                html_escape(command_info.description()),
+            )),
+            Some(reason) => body.push_str(&format!(
+                "<li>{} (disabled: {})</li>\n",
+                html_escape(command_info.description()),
+                html_escape(reason),
+            )),
+        }
+    }
+
+    body.push_str("</ul>\n");
+
+    html_page("Commands", &body)
+}
+
+pub fn exit_status_text(response: &RunCommandResponse<'_>) -> String {
+    match (&response.error, response.exit_code, response.signal) {
+        (Some(error), _, _) => error.clone(),
+        (None, Some(exit_code), _) => format!("exit code {}", exit_code),
+        (None, None, Some(signal)) => format!("terminated by signal {}", signal),
+        (None, None, None) => "unknown".to_owned(),
+    }
+}
+
+pub fn run_command_html(response: &RunCommandResponse<'_>) -> String {
+    let mut body = format!(
+        "<h2>{}</h2>\n<p>Now: {}<br>\nCommand duration: {} ms<br>\nExit status: {}</p>\n",
+        html_escape(response.command_info.description()),
+        html_escape(&response.now),
+        response.command_duration_ms,
+        html_escape(&exit_status_text(response)),
+    );
+
+    if !response.stderr.is_empty() {
+        body.push_str(&format!(
+            "<h3>stderr</h3>\n<pre>{}</pre>\n",
+            html_escape(&response.stderr)
+        ));
+    }
+
+    body.push_str(&format!(
+        "<h3>stdout</h3>\n<pre>{}</pre>\n",
+        html_escape(&response.stdout)
+    ));
+
+    html_page(response.command_info.description(), &body)
+}
+
+fn history_status_text(entry: &HistoryEntry) -> String {
+    match (&entry.error, entry.timed_out, entry.exit_code, entry.signal) {
+        (Some(error), _, _, _) => error.clone(),
+        (None, true, _, _) => "timed out".to_owned(),
+        (None, false, Some(exit_code), _) => format!("exit code {}", exit_code),
+        (None, false, None, Some(signal)) => format!("terminated by signal {}", signal),
+        (None, false, None, None) => "unknown".to_owned(),
+    }
+}
+
+pub fn history_text(entries: &[HistoryEntry]) -> String {
+    let mut text = String::new();
+
+    for entry in entries {
+        text.push_str(&format!(
+            "{}\t{} ms\t{}\t{}\n",
+            entry.time,
+            entry.duration_ms,
+            history_status_text(entry),
+            entry.caller,
+        ));
+    }
+
+    text
+}
+
+pub fn history_html(command_info: &CommandInfo, entries: &[HistoryEntry]) -> String {
+    let title = format!("{} history", command_info.description());
+
+    let mut body = format!(
+        "<h2>{}</h2>\n<table>\n<tr><th>Time</th><th>Duration</th><th>Status</th><th>Caller</th></tr>\n",
+        html_escape(&title)
+    );
+
+    for entry in entries {
+        body.push_str(&format!(
+            "<tr><td>{}</td><td>{} ms</td><td>{}</td><td>{}</td></tr>\n",
+            html_escape(&entry.time),
+            entry.duration_ms,
+            html_escape(&history_status_text(entry)),
+            html_escape(&entry.caller),
+        ));
+    }
+
+    body.push_str("</table>\n");
+
+    html_page(&title, &body)
+}
@@ -0,0 +1,429 @@
+use std::{
+    os::{fd::OwnedFd, unix::process::CommandExt},
+    process::{ExitStatus, Stdio},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use log::{info, warn};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::unix::pipe,
+    process::{Child, ChildStdin, Command},
+    time::Duration,
+};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::{CommandInfo, OutputLimitAction},
+    utils::GenericAsyncReader,
+};
+
+use super::process::ProcessOptions;
+
+// Time allowed to read output still buffered in the pipes after killing a timed out command.
+const PIPE_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Default)]
+pub struct CapturedOutput {
+    pub data: Vec<u8>,
+    pub truncated_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct CommandRunOutput {
+    // Status of the last stage for a pipeline.
+    pub status: Option<ExitStatus>,
+    // Status of every stage of a pipeline, in order.
+    pub stage_statuses: Vec<ExitStatus>,
+    pub stdout: CapturedOutput,
+    pub stderr: CapturedOutput,
+    pub timed_out: bool,
+    pub cancelled: bool,
+    pub output_limit_exceeded: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+// Receives captured output while the command runs.
+pub trait OutputObserver: Send + Sync {
+    fn output(&self, stream: OutputStream, data: &[u8]);
+}
+
+// Optional ways to follow and stop a run while it is in progress.
+#[derive(Clone, Copy, Default)]
+pub struct RunHooks<'a> {
+    pub output_observer: Option<&'a dyn OutputObserver>,
+    // Cancelling kills the command's process group.
+    pub cancellation_token: Option<&'a CancellationToken>,
+}
+
+enum RunEnd<T> {
+    Completed(T),
+    TimedOut,
+    Cancelled,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct OutputLimit {
+    max_bytes: usize,
+    action: OutputLimitAction,
+}
+
+// Each command runs as the leader of a new process group so the command and
+// anything it started can be killed together.  The group is killed on drop
+// unless the command ran to completion.
+struct ProcessGroup {
+    // 0 once the group has been killed or disarmed.
+    pgid: AtomicI32,
+}
+
+impl ProcessGroup {
+    fn new(pid: Option<u32>) -> Self {
+        Self {
+            pgid: AtomicI32::new(pid.unwrap_or(0) as libc::pid_t),
+        }
+    }
+
+    fn kill(&self) {
+        let pgid = self.pgid.swap(0, Ordering::Relaxed);
+        if pgid != 0 {
+            // Safety: kill has no memory safety requirements.
+            if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+                warn!(
+                    "kill process group {} error: {}",
+                    pgid,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    fn disarm(&self) {
+        self.pgid.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+// Reads pipe until EOF, capturing at most output_limit.max_bytes.
+// Returns true if the limit was exceeded.
+async fn read_pipe(
+    pipe: &mut Option<impl GenericAsyncReader>,
+    output: &mut CapturedOutput,
+    output_limit: OutputLimit,
+    process_group: &ProcessGroup,
+    output_stream: OutputStream,
+    output_observer: Option<&dyn OutputObserver>,
+) -> Result<bool, std::io::Error> {
+    let pipe = match pipe {
+        None => return Ok(false),
+        Some(pipe) => pipe,
+    };
+
+    let mut buffer = [0u8; READ_BUFFER_SIZE];
+
+    loop {
+        let bytes_read = pipe.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            return Ok(output.truncated_bytes > 0);
+        }
+
+        let capture_bytes = bytes_read.min(output_limit.max_bytes - output.data.len());
+        output.data.extend_from_slice(&buffer[..capture_bytes]);
+
+        if let Some(output_observer) = output_observer {
+            if capture_bytes > 0 {
+                output_observer.output(output_stream, &buffer[..capture_bytes]);
+            }
+        }
+
+        if capture_bytes < bytes_read {
+            if output.truncated_bytes == 0 {
+                if let OutputLimitAction::Kill = output_limit.action {
+                    process_group.kill();
+                }
+            }
+            output.truncated_bytes += (bytes_read - capture_bytes) as u64;
+        }
+    }
+}
+
+// Writes data to the command's stdin pipe, if it has one, then closes it.
+async fn write_stdin(pipe: Option<ChildStdin>, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut pipe = match pipe {
+        None => return Ok(()),
+        Some(pipe) => pipe,
+    };
+
+    match pipe.write_all(data).await {
+        // The command exited or closed stdin without reading all of it.
+        Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+// Waits for every stage, returning their statuses in order.
+async fn wait_all(children: &mut [Child]) -> Result<Vec<ExitStatus>, std::io::Error> {
+    let mut statuses = Vec::with_capacity(children.len());
+    for child in children {
+        statuses.push(child.wait().await?);
+    }
+    Ok(statuses)
+}
+
+pub struct CommandRunner {
+    command_info: &'static CommandInfo,
+    timeout: Duration,
+    output_limit: OutputLimit,
+    process_options: ProcessOptions,
+}
+
+impl CommandRunner {
+    pub fn new(command_info: &'static CommandInfo) -> anyhow::Result<Self> {
+        let command_configuration = crate::config::instance().command_configuration();
+
+        let process_options = ProcessOptions::new(command_info)?;
+
+        process_options.check_executable(command_info.command())?;
+
+        for stage in command_info.pipeline() {
+            anyhow::ensure!(
+                !stage.command().is_empty(),
+                "pipeline stage command must not be empty"
+            );
+            process_options.check_executable(stage.command())?;
+        }
+
+        Ok(Self {
+            command_info,
+            timeout: command_info
+                .timeout()
+                .unwrap_or(*command_configuration.command_timeout()),
+            output_limit: OutputLimit {
+                max_bytes: command_info
+                    .max_output_bytes()
+                    .unwrap_or(*command_configuration.max_output_bytes()),
+                action: *command_info.output_limit_action(),
+            },
+            process_options,
+        })
+    }
+
+    async fn spawn_stage(
+        &self,
+        command: &str,
+        args: &[String],
+        process_group_id: libc::pid_t,
+        stdin: Option<Stdio>,
+        stderr: Stdio,
+    ) -> Result<Child, std::io::Error> {
+        let mut std_command = std::process::Command::new(command);
+
+        std_command
+            .args(args)
+            .stdout(Stdio::piped())
+            .process_group(process_group_id);
+
+        self.process_options.configure(&mut std_command).await?;
+
+        if let Some(stdin) = stdin {
+            std_command.stdin(stdin);
+        }
+
+        std_command.stderr(stderr);
+
+        Command::from(std_command).kill_on_drop(true).spawn()
+    }
+
+    pub async fn run(
+        &self,
+        args: &[String],
+        stdin: &[u8],
+        run_hooks: RunHooks<'_>,
+    ) -> Result<CommandRunOutput, std::io::Error> {
+        let command_info = self.command_info;
+        let timeout = self.timeout;
+        let output_limit = self.output_limit;
+
+        // All stages of a pipeline write stderr to one pipe, so it is read in the order written.
+        let (stderr_reader, stderr_writer) = if command_info.pipeline().is_empty() {
+            (None, None)
+        } else {
+            let (stderr_reader, stderr_writer) = std::io::pipe()?;
+            (Some(stderr_reader), Some(stderr_writer))
+        };
+
+        let stderr_stdio = || -> Result<Stdio, std::io::Error> {
+            Ok(match &stderr_writer {
+                None => Stdio::piped(),
+                Some(stderr_writer) => Stdio::from(stderr_writer.try_clone()?),
+            })
+        };
+
+        let mut children = Vec::with_capacity(1 + command_info.pipeline().len());
+
+        children.push(
+            self.spawn_stage(command_info.command(), args, 0, None, stderr_stdio()?)
+                .await?,
+        );
+
+        let process_group = ProcessGroup::new(children[0].id());
+
+        // Later stages join the first stage's process group and read the previous stdout.
+        for stage in command_info.pipeline() {
+            let previous_stdout = match children.last_mut().and_then(|child| child.stdout.take()) {
+                None => None,
+                Some(previous_stdout) => Some(previous_stdout.try_into()?),
+            };
+            children.push(
+                self.spawn_stage(
+                    stage.command(),
+                    stage.args(),
+                    children[0].id().unwrap_or(0) as libc::pid_t,
+                    previous_stdout,
+                    stderr_stdio()?,
+                )
+                .await?,
+            );
+        }
+
+        // Only the stages hold the write end, so the reader sees EOF once they all exit.
+        drop(stderr_writer);
+
+        let stdin_pipe = children[0].stdin.take();
+        let mut stdout_pipe = children.last_mut().and_then(|child| child.stdout.take());
+        let mut stderr_pipe: Option<Box<dyn GenericAsyncReader + Send>> = match stderr_reader {
+            None => children[0]
+                .stderr
+                .take()
+                .map(|stderr| Box::new(stderr) as Box<dyn GenericAsyncReader + Send>),
+            Some(stderr_reader) => Some(Box::new(pipe::Receiver::from_owned_fd(OwnedFd::from(
+                stderr_reader,
+            ))?)),
+        };
+
+        let mut output = CommandRunOutput::default();
+
+        let cancelled = async {
+            match run_hooks.cancellation_token {
+                Some(cancellation_token) => cancellation_token.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let run_end = tokio::select! {
+            result = tokio::time::timeout(timeout, async {
+                let (stdin_result, stdout_result, stderr_result, wait_result) = tokio::join!(
+                    write_stdin(stdin_pipe, stdin),
+                    read_pipe(
+                        &mut stdout_pipe,
+                        &mut output.stdout,
+                        output_limit,
+                        &process_group,
+                        OutputStream::Stdout,
+                        run_hooks.output_observer,
+                    ),
+                    read_pipe(
+                        &mut stderr_pipe,
+                        &mut output.stderr,
+                        output_limit,
+                        &process_group,
+                        OutputStream::Stderr,
+                        run_hooks.output_observer,
+                    ),
+                    wait_all(&mut children),
+                );
+                stdin_result?;
+                let stdout_limit_exceeded = stdout_result?;
+                let stderr_limit_exceeded = stderr_result?;
+                Ok::<_, std::io::Error>((stdout_limit_exceeded || stderr_limit_exceeded, wait_result?))
+            }) => match result {
+                Ok(result) => RunEnd::Completed(result),
+                Err(_) => RunEnd::TimedOut,
+            },
+            _ = cancelled => RunEnd::Cancelled,
+        };
+
+        match run_end {
+            RunEnd::Completed(result) => {
+                process_group.disarm();
+                let (output_limit_exceeded, statuses) = result?;
+                if output_limit_exceeded {
+                    warn!(
+                        "command '{}' exceeded output limit of {} bytes",
+                        command_info.id(),
+                        output_limit.max_bytes,
+                    );
+                }
+                output.output_limit_exceeded = output_limit_exceeded;
+                output.status = statuses.last().copied();
+                if !command_info.pipeline().is_empty() {
+                    output.stage_statuses = statuses;
+                }
+            }
+            RunEnd::TimedOut | RunEnd::Cancelled => {
+                if let RunEnd::TimedOut = run_end {
+                    warn!(
+                        "command '{}' timed out after {:?}, killing process group",
+                        command_info.id(),
+                        timeout,
+                    );
+                    output.timed_out = true;
+                } else {
+                    info!(
+                        "command '{}' cancelled, killing process group",
+                        command_info.id(),
+                    );
+                    output.cancelled = true;
+                }
+
+                process_group.kill();
+
+                let statuses = wait_all(&mut children).await?;
+                output.status = statuses.last().copied();
+                if !command_info.pipeline().is_empty() {
+                    output.stage_statuses = statuses;
+                }
+
+                let _ = tokio::time::timeout(PIPE_DRAIN_TIMEOUT, async {
+                    tokio::join!(
+                        read_pipe(
+                            &mut stdout_pipe,
+                            &mut output.stdout,
+                            output_limit,
+                            &process_group,
+                            OutputStream::Stdout,
+                            run_hooks.output_observer,
+                        ),
+                        read_pipe(
+                            &mut stderr_pipe,
+                            &mut output.stderr,
+                            output_limit,
+                            &process_group,
+                            OutputStream::Stderr,
+                            run_hooks.output_observer,
+                        ),
+                    )
+                })
+                .await;
+
+                output.output_limit_exceeded =
+                    output.stdout.truncated_bytes > 0 || output.stderr.truncated_bytes > 0;
+            }
+        }
+
+        Ok(output)
+    }
+}
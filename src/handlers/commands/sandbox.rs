@@ -0,0 +1,290 @@
+use std::{
+    ffi::CString,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        process::CommandExt,
+    },
+};
+
+use anyhow::Context;
+
+use log::info;
+
+use crate::config::SandboxConfiguration;
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+// Filesystem access rights from landlock ABI version 1.
+const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const LANDLOCK_ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+fn check_result(result: libc::c_long) -> Result<libc::c_long, std::io::Error> {
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+// Sandbox settings that can be applied in the child after fork.
+#[derive(Clone, Copy, Debug, Default)]
+struct ChildSandbox {
+    unshare_flags: libc::c_int,
+    gid: Option<libc::gid_t>,
+    uid: Option<libc::uid_t>,
+    no_new_privs: bool,
+    landlock_ruleset_fd: Option<RawFd>,
+}
+
+impl ChildSandbox {
+    fn is_empty(&self) -> bool {
+        self.unshare_flags == 0
+            && self.gid.is_none()
+            && self.uid.is_none()
+            && !self.no_new_privs
+            && self.landlock_ruleset_fd.is_none()
+    }
+
+    // Called in the child between fork and exec, so only async-signal-safe calls are allowed.
+    // Namespaces are unshared before dropping privileges, and landlock is applied last.
+    fn apply(&self) -> Result<(), std::io::Error> {
+        if self.unshare_flags != 0 {
+            // Safety: unshare has no memory safety requirements.
+            check_result(unsafe { libc::unshare(self.unshare_flags) }.into())?;
+        }
+
+        if let Some(gid) = self.gid {
+            // Safety: gid is a valid pointer to one gid_t for the duration of the call.
+            check_result(unsafe { libc::setgroups(1, &gid) }.into())?;
+            // Safety: setgid has no memory safety requirements.
+            check_result(unsafe { libc::setgid(gid) }.into())?;
+        }
+
+        if let Some(uid) = self.uid {
+            // Safety: setuid has no memory safety requirements.
+            check_result(unsafe { libc::setuid(uid) }.into())?;
+        }
+
+        if self.no_new_privs {
+            // Safety: prctl(PR_SET_NO_NEW_PRIVS) has no memory safety requirements.
+            check_result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) }.into())?;
+        }
+
+        if let Some(landlock_ruleset_fd) = self.landlock_ruleset_fd {
+            // Safety: landlock_restrict_self has no memory safety requirements.
+            check_result(unsafe {
+                libc::syscall(libc::SYS_landlock_restrict_self, landlock_ruleset_fd, 0)
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+// Runs apply in a forked child that exits before exec, to check at startup that the
+// kernel and our privileges support a setting, such as a sandbox option.
+pub fn probe(
+    setting: &str,
+    apply: impl Fn() -> Result<(), std::io::Error> + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    let current_exe = std::env::current_exe().context("current_exe error")?;
+
+    let mut command = std::process::Command::new(current_exe);
+
+    // Safety: apply only makes async-signal-safe calls.
+    unsafe {
+        command.pre_exec(move || {
+            apply()?;
+            // Report success without running exec.
+            Err(std::io::Error::from_raw_os_error(libc::ECANCELED))
+        });
+    }
+
+    match command.spawn() {
+        Err(err) if err.raw_os_error() == Some(libc::ECANCELED) => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("{} is not supported", setting)),
+        Ok(mut child) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("{} probe unexpectedly ran exec", setting)
+        }
+    }
+}
+
+fn landlock_abi_version() -> anyhow::Result<libc::c_long> {
+    // Safety: a null attr with size 0 is valid with LANDLOCK_CREATE_RULESET_VERSION.
+    check_result(unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<LandlockRulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    })
+    .context("landlock is not supported or not enabled by this kernel")
+}
+
+// Creates a landlock ruleset allowing only read and execute access beneath read_only_paths.
+fn create_landlock_ruleset(read_only_paths: &[std::path::PathBuf]) -> anyhow::Result<OwnedFd> {
+    let abi_version = landlock_abi_version()?;
+
+    info!("landlock abi_version = {}", abi_version);
+
+    let ruleset_attr = LandlockRulesetAttr {
+        handled_access_fs: LANDLOCK_ACCESS_FS_ABI_1,
+    };
+
+    // Safety: ruleset_attr is valid for the duration of the call.
+    let ruleset_fd = check_result(unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &ruleset_attr,
+            std::mem::size_of::<LandlockRulesetAttr>(),
+            0,
+        )
+    })
+    .context("landlock_create_ruleset error")?;
+
+    // Safety: ruleset_fd is a newly created file descriptor that we own.
+    let ruleset_fd = unsafe { OwnedFd::from_raw_fd(ruleset_fd as RawFd) };
+
+    for path in read_only_paths {
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("landlock path '{}' error", path.display()))?;
+
+        let allowed_access = if metadata.is_dir() {
+            LANDLOCK_ACCESS_FS_EXECUTE | LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_READ_DIR
+        } else {
+            LANDLOCK_ACCESS_FS_EXECUTE | LANDLOCK_ACCESS_FS_READ_FILE
+        };
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .with_context(|| format!("landlock path '{}' contains NUL", path.display()))?;
+
+        // Safety: c_path is a valid NUL terminated string.
+        let path_fd = check_result(
+            unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) }.into(),
+        )
+        .with_context(|| format!("landlock path '{}' open error", path.display()))?;
+
+        // Safety: path_fd is a newly opened file descriptor that we own.
+        let path_fd = unsafe { OwnedFd::from_raw_fd(path_fd as RawFd) };
+
+        let path_beneath_attr = LandlockPathBeneathAttr {
+            allowed_access,
+            parent_fd: path_fd.as_raw_fd(),
+        };
+
+        // Safety: path_beneath_attr is valid for the duration of the call.
+        check_result(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset_fd.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &path_beneath_attr,
+                0,
+            )
+        })
+        .with_context(|| format!("landlock_add_rule error path '{}'", path.display()))?;
+    }
+
+    Ok(ruleset_fd)
+}
+
+pub struct Sandbox {
+    child_sandbox: ChildSandbox,
+    // Kept open for the life of the server, every child restricts itself with this ruleset.
+    _landlock_ruleset_fd: Option<OwnedFd>,
+}
+
+impl Sandbox {
+    pub fn new(sandbox_configuration: &SandboxConfiguration) -> anyhow::Result<Self> {
+        let mut child_sandbox = ChildSandbox::default();
+
+        for (enabled, flag) in [
+            (*sandbox_configuration.unshare_user(), libc::CLONE_NEWUSER),
+            (*sandbox_configuration.unshare_network(), libc::CLONE_NEWNET),
+            (*sandbox_configuration.unshare_pid(), libc::CLONE_NEWPID),
+            (*sandbox_configuration.unshare_mount(), libc::CLONE_NEWNS),
+        ] {
+            if enabled {
+                child_sandbox.unshare_flags |= flag;
+            }
+        }
+
+        if child_sandbox.unshare_flags != 0 {
+            let probe_sandbox = ChildSandbox {
+                unshare_flags: child_sandbox.unshare_flags,
+                ..Default::default()
+            };
+            probe("sandbox option unshare", move || probe_sandbox.apply())?;
+        }
+
+        if sandbox_configuration.gid().is_some() || sandbox_configuration.uid().is_some() {
+            let probe_sandbox = ChildSandbox {
+                gid: *sandbox_configuration.gid(),
+                uid: *sandbox_configuration.uid(),
+                ..Default::default()
+            };
+            probe("sandbox option uid/gid", move || probe_sandbox.apply())?;
+
+            child_sandbox.gid = probe_sandbox.gid;
+            child_sandbox.uid = probe_sandbox.uid;
+        }
+
+        let landlock_ruleset_fd = if sandbox_configuration.landlock_read_only_paths().is_empty() {
+            None
+        } else {
+            Some(create_landlock_ruleset(
+                sandbox_configuration.landlock_read_only_paths(),
+            )?)
+        };
+
+        // landlock_restrict_self requires no_new_privs for unprivileged processes.
+        child_sandbox.no_new_privs =
+            *sandbox_configuration.no_new_privs() || landlock_ruleset_fd.is_some();
+
+        child_sandbox.landlock_ruleset_fd = landlock_ruleset_fd.as_ref().map(AsRawFd::as_raw_fd);
+
+        if child_sandbox.no_new_privs {
+            let probe_sandbox = ChildSandbox {
+                no_new_privs: true,
+                landlock_ruleset_fd: child_sandbox.landlock_ruleset_fd,
+                ..Default::default()
+            };
+            probe("sandbox option no_new_privs/landlock", move || {
+                probe_sandbox.apply()
+            })?;
+        }
+
+        Ok(Self {
+            child_sandbox,
+            _landlock_ruleset_fd: landlock_ruleset_fd,
+        })
+    }
+
+    pub fn configure(&self, command: &mut std::process::Command) {
+        if !self.child_sandbox.is_empty() {
+            let child_sandbox = self.child_sandbox;
+            // Safety: ChildSandbox::apply only makes async-signal-safe calls.
+            unsafe {
+                command.pre_exec(move || child_sandbox.apply());
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+
+use chrono::Local;
+
+use tokio::time::{Duration, Instant};
+
+use crate::config::CommandSchedule;
+
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    pub fn new(command_schedule: &CommandSchedule) -> anyhow::Result<Self> {
+        Ok(match command_schedule {
+            CommandSchedule::Interval(interval) => {
+                anyhow::ensure!(!interval.is_zero(), "schedule interval must not be zero");
+                Self::Interval(*interval)
+            }
+            CommandSchedule::Cron(expression) => Self::Cron(Box::new(
+                cron::Schedule::from_str(expression)
+                    .with_context(|| format!("invalid cron expression '{}'", expression))?,
+            )),
+        })
+    }
+
+    // Returns when to run next, or None if a cron schedule has no more times.
+    pub fn next_run_time(&self, previous_run_time: Instant) -> Option<Instant> {
+        match self {
+            Self::Interval(interval) => Some(previous_run_time + *interval),
+            Self::Cron(schedule) => {
+                let next = schedule.upcoming(Local).next()?;
+                let delay = (next - Local::now()).to_std().unwrap_or_default();
+                Some(Instant::now() + delay)
+            }
+        }
+    }
+}
+
+pub struct Snapshot<T> {
+    pub value: Arc<T>,
+    pub stored_time: Instant,
+    pub last_run_failed: bool,
+}
+
+impl<T> Clone for Snapshot<T> {
+    fn clone(&self) -> Self {
+        Self {
+            value: Arc::clone(&self.value),
+            stored_time: self.stored_time,
+            last_run_failed: self.last_run_failed,
+        }
+    }
+}
+
+// Latest result of a scheduled command.  A failed run keeps the previous successful
+// result and only marks it, so clients see the last good output.
+pub struct LatestResult<T> {
+    snapshot: Mutex<Option<Snapshot<T>>>,
+}
+
+impl<T> LatestResult<T> {
+    pub fn new() -> Self {
+        Self {
+            snapshot: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Option<Snapshot<T>> {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    // Records a run.  value is None if the run produced no result at all.
+    pub fn record(&self, value: Option<Arc<T>>, success: bool) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+
+        match (value, snapshot.as_mut()) {
+            (Some(value), None) => {
+                *snapshot = Some(Snapshot {
+                    value,
+                    stored_time: Instant::now(),
+                    last_run_failed: !success,
+                });
+            }
+            (Some(value), Some(previous)) if success => {
+                *previous = Snapshot {
+                    value,
+                    stored_time: Instant::now(),
+                    last_run_failed: false,
+                };
+            }
+            (_, Some(previous)) => previous.last_run_failed = !success,
+            (None, None) => {}
+        }
+    }
+}
+
+pub struct ScheduledCommand<T> {
+    pub schedule: Schedule,
+    pub args: Vec<String>,
+    pub latest_result: LatestResult<T>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(latest_result: &LatestResult<u32>) -> Option<(u32, bool)> {
+        latest_result
+            .get()
+            .map(|snapshot| (*snapshot.value, snapshot.last_run_failed))
+    }
+
+    #[test]
+    fn failed_run_keeps_previous_good_result() {
+        let latest_result = LatestResult::new();
+
+        latest_result.record(Some(Arc::new(1)), true);
+        assert_eq!(snapshot(&latest_result), Some((1, false)));
+
+        latest_result.record(Some(Arc::new(2)), false);
+        assert_eq!(snapshot(&latest_result), Some((1, true)));
+
+        latest_result.record(None, false);
+        assert_eq!(snapshot(&latest_result), Some((1, true)));
+
+        latest_result.record(Some(Arc::new(3)), true);
+        assert_eq!(snapshot(&latest_result), Some((3, false)));
+    }
+
+    #[test]
+    fn failed_first_run_is_served_until_a_good_one() {
+        let latest_result = LatestResult::new();
+
+        latest_result.record(None, false);
+        assert_eq!(snapshot(&latest_result), None);
+
+        latest_result.record(Some(Arc::new(1)), false);
+        assert_eq!(snapshot(&latest_result), Some((1, true)));
+
+        latest_result.record(Some(Arc::new(2)), true);
+        assert_eq!(snapshot(&latest_result), Some((2, false)));
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Context;
+
+use serde::Serialize;
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{Duration, Instant},
+};
+
+use crate::config::{CommandConfiguration, CommandInfo};
+
+const RECENT_DURATIONS_SIZE: usize = 16;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SemaphoreDiagnostics {
+    pub semaphore: String,
+    pub queue_depth: usize,
+    pub available_permits: usize,
+    pub max_permits: usize,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RunCommandSemaporeAcquireError {
+    #[error("acquire timeout: {0:?}")]
+    Timeout(SemaphoreDiagnostics),
+
+    #[error("acquire error: {0}")]
+    AcquireError(#[from] tokio::sync::AcquireError),
+}
+
+pub struct RunCommandSemapore {
+    name: String,
+    semapore: Arc<Semaphore>,
+    max_permits: usize,
+    acquire_timeout: Duration,
+    queue_depth: AtomicUsize,
+}
+
+// Counts a task waiting on a RunCommandSemapore until dropped.
+struct QueueEntry<'a> {
+    queue_depth: &'a AtomicUsize,
+}
+
+impl<'a> QueueEntry<'a> {
+    fn new(queue_depth: &'a AtomicUsize) -> Self {
+        queue_depth.fetch_add(1, Ordering::Relaxed);
+        Self { queue_depth }
+    }
+}
+
+impl<'a> Drop for QueueEntry<'a> {
+    fn drop(&mut self) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RunCommandSemapore {
+    fn new(name: String, max_concurrent_commands: usize, acquire_timeout: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            semapore: Arc::new(Semaphore::new(max_concurrent_commands)),
+            max_permits: max_concurrent_commands,
+            acquire_timeout,
+            queue_depth: AtomicUsize::new(0),
+        })
+    }
+
+    fn diagnostics(&self) -> SemaphoreDiagnostics {
+        SemaphoreDiagnostics {
+            semaphore: self.name.clone(),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            available_permits: self.semapore.available_permits(),
+            max_permits: self.max_permits,
+        }
+    }
+}
+
+// Permits held while a command runs, released on drop.
+pub struct RunCommandPermits {
+    _permits: Vec<OwnedSemaphorePermit>,
+}
+
+// The semaphores a command must acquire before running: its own limit if configured,
+// then its semaphore group if any, then the global limit.
+pub struct CommandSemaphores {
+    semaphores: Vec<Arc<RunCommandSemapore>>,
+    acquire_timeout: Duration,
+    max_acquire_timeout: Duration,
+}
+
+impl CommandSemaphores {
+    // Clients may ask to wait longer than the configured acquire timeout, up to max_acquire_timeout.
+    pub fn acquire_timeout(&self, requested_acquire_timeout: Option<Duration>) -> Duration {
+        match requested_acquire_timeout {
+            None => self.acquire_timeout,
+            Some(requested_acquire_timeout) => requested_acquire_timeout
+                .min(self.max_acquire_timeout)
+                .max(self.acquire_timeout),
+        }
+    }
+
+    pub async fn acquire(
+        &self,
+        acquire_timeout: Duration,
+    ) -> Result<RunCommandPermits, RunCommandSemaporeAcquireError> {
+        let deadline = Instant::now() + acquire_timeout;
+
+        let mut permits = Vec::with_capacity(self.semaphores.len());
+
+        for semaphore in &self.semaphores {
+            let queue_entry = QueueEntry::new(&semaphore.queue_depth);
+
+            let result =
+                tokio::time::timeout_at(deadline, Arc::clone(&semaphore.semapore).acquire_owned())
+                    .await;
+
+            drop(queue_entry);
+
+            match result {
+                Err(_) => {
+                    return Err(RunCommandSemaporeAcquireError::Timeout(
+                        semaphore.diagnostics(),
+                    ))
+                }
+                Ok(permit) => permits.push(permit?),
+            }
+        }
+
+        Ok(RunCommandPermits { _permits: permits })
+    }
+}
+
+// Durations of the most recent runs of a command, used to estimate Retry-After.
+pub struct RecentDurations {
+    durations: Mutex<VecDeque<Duration>>,
+}
+
+impl RecentDurations {
+    pub fn new() -> Self {
+        Self {
+            durations: Mutex::new(VecDeque::with_capacity(RECENT_DURATIONS_SIZE)),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let mut durations = self.durations.lock().unwrap();
+        if durations.len() == RECENT_DURATIONS_SIZE {
+            durations.pop_front();
+        }
+        durations.push_back(duration);
+    }
+
+    fn average(&self) -> Option<Duration> {
+        let durations = self.durations.lock().unwrap();
+        if durations.is_empty() {
+            None
+        } else {
+            Some(durations.iter().sum::<Duration>() / durations.len() as u32)
+        }
+    }
+
+    // Estimates how long until a permit frees up: every queued waiter ahead of us
+    // needs one average run time spread across the semaphore's permits.
+    pub fn estimate_retry_after_seconds(&self, diagnostics: &SemaphoreDiagnostics) -> u64 {
+        let average = self.average().unwrap_or(Duration::from_secs(1));
+
+        let rounds = 1 + diagnostics.queue_depth / diagnostics.max_permits.max(1);
+
+        let estimate = average * rounds as u32;
+
+        estimate.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+pub struct CommandSemaphoresFactory {
+    global_semaphore: Arc<RunCommandSemapore>,
+    group_semaphores: BTreeMap<&'static str, Arc<RunCommandSemapore>>,
+    max_acquire_timeout: Duration,
+}
+
+impl CommandSemaphoresFactory {
+    pub fn new(command_configuration: &'static CommandConfiguration) -> Self {
+        let global_acquire_timeout = *command_configuration.semaphore_acquire_timeout();
+
+        Self {
+            global_semaphore: RunCommandSemapore::new(
+                "global".to_owned(),
+                *command_configuration.max_concurrent_commands(),
+                global_acquire_timeout,
+            ),
+            group_semaphores: command_configuration
+                .semaphore_groups()
+                .iter()
+                .map(|(name, group)| {
+                    (
+                        name.as_str(),
+                        RunCommandSemapore::new(
+                            format!("group:{}", name),
+                            *group.max_concurrent_commands(),
+                            group
+                                .semaphore_acquire_timeout()
+                                .unwrap_or(global_acquire_timeout),
+                        ),
+                    )
+                })
+                .collect(),
+            max_acquire_timeout: command_configuration
+                .max_semaphore_acquire_timeout()
+                .unwrap_or(global_acquire_timeout),
+        }
+    }
+
+    pub fn command_semaphores(
+        &self,
+        command_info: &CommandInfo,
+    ) -> anyhow::Result<CommandSemaphores> {
+        let mut semaphores = Vec::with_capacity(3);
+
+        // Most specific acquire_timeout wins.
+        let mut acquire_timeout = self.global_semaphore.acquire_timeout;
+
+        if let Some(group_name) = command_info.semaphore_group() {
+            let group_semaphore = self
+                .group_semaphores
+                .get(group_name.as_str())
+                .with_context(|| format!("unknown semaphore_group '{}'", group_name))?;
+
+            acquire_timeout = group_semaphore.acquire_timeout;
+        }
+
+        if let Some(command_acquire_timeout) = command_info.semaphore_acquire_timeout() {
+            acquire_timeout = *command_acquire_timeout;
+        }
+
+        if let Some(max_concurrent) = command_info.max_concurrent() {
+            semaphores.push(RunCommandSemapore::new(
+                format!("command:{}", command_info.id()),
+                *max_concurrent,
+                acquire_timeout,
+            ));
+        }
+
+        if let Some(group_name) = command_info.semaphore_group() {
+            semaphores.push(Arc::clone(&self.group_semaphores[group_name.as_str()]));
+        }
+
+        semaphores.push(Arc::clone(&self.global_semaphore));
+
+        Ok(CommandSemaphores {
+            semaphores,
+            acquire_timeout,
+            max_acquire_timeout: self.max_acquire_timeout,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_semaphores(semaphores: Vec<Arc<RunCommandSemapore>>) -> CommandSemaphores {
+        CommandSemaphores {
+            semaphores,
+            acquire_timeout: Duration::from_millis(20),
+            max_acquire_timeout: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn requested_acquire_timeout_is_clamped() {
+        let command_semaphores = command_semaphores(Vec::new());
+
+        for (requested, expected) in [(None, 20), (Some(5), 20), (Some(50), 50), (Some(1000), 100)]
+        {
+            assert_eq!(
+                command_semaphores.acquire_timeout(requested.map(Duration::from_millis)),
+                Duration::from_millis(expected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn command_semaphore_is_acquired_before_global() {
+        let command = RunCommandSemapore::new("command:a".to_owned(), 1, Duration::ZERO);
+        let global = RunCommandSemapore::new("global".to_owned(), 2, Duration::ZERO);
+        let command_semaphores =
+            command_semaphores(vec![Arc::clone(&command), Arc::clone(&global)]);
+
+        let _permits = command_semaphores.acquire(Duration::ZERO).await.unwrap();
+        assert_eq!(global.semapore.available_permits(), 1);
+
+        // Waiting on the command limit does not hold a global permit.
+        match command_semaphores.acquire(Duration::from_millis(20)).await {
+            Err(RunCommandSemaporeAcquireError::Timeout(diagnostics)) => {
+                assert_eq!(diagnostics.semaphore, "command:a");
+            }
+            _ => panic!("acquire did not time out"),
+        }
+        assert_eq!(global.semapore.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn timeout_names_the_semaphore_waited_on() {
+        let command = RunCommandSemapore::new("command:a".to_owned(), 1, Duration::ZERO);
+        let global = RunCommandSemapore::new("global".to_owned(), 1, Duration::ZERO);
+        let command_semaphores =
+            command_semaphores(vec![Arc::clone(&command), Arc::clone(&global)]);
+
+        let _global_permit = Arc::clone(&global.semapore).acquire_owned().await;
+
+        match command_semaphores.acquire(Duration::from_millis(20)).await {
+            Err(RunCommandSemaporeAcquireError::Timeout(diagnostics)) => {
+                assert_eq!(diagnostics.semaphore, "global");
+                assert_eq!(diagnostics.queue_depth, 0);
+                assert_eq!(diagnostics.available_permits, 0);
+                assert_eq!(diagnostics.max_permits, 1);
+            }
+            _ => panic!("acquire did not time out"),
+        }
+
+        // The command permit acquired first is released again.
+        assert_eq!(command.semapore.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn waiters_are_counted_in_queue_depth() {
+        let global = RunCommandSemapore::new("global".to_owned(), 1, Duration::ZERO);
+        let command_semaphores = command_semaphores(vec![Arc::clone(&global)]);
+
+        let permits = command_semaphores.acquire(Duration::ZERO).await.unwrap();
+
+        let waiter = command_semaphores.acquire(Duration::from_millis(100));
+        let check = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(global.diagnostics().queue_depth, 1);
+            drop(permits);
+        };
+
+        let (result, ()) = tokio::join!(waiter, check);
+        assert!(result.is_ok());
+        assert_eq!(global.diagnostics().queue_depth, 0);
+    }
+}
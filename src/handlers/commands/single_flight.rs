@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OnceCell;
+
+type InFlight<T> = Arc<OnceCell<Arc<T>>>;
+
+// Joins concurrent calls with the same key into one run.
+pub struct SingleFlight<T> {
+    in_flight: Mutex<HashMap<Vec<String>, InFlight<T>>>,
+}
+
+impl<T> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Runs run unless a call with the same key is already in flight, in which case waits
+    // for its result.  Returns the result and whether it came from another call's run.
+    // Errors are not shared, a waiter whose leader fails runs again.
+    pub async fn run<F, Fut, E>(&self, key: &[String], run: F) -> Result<(Arc<T>, bool), E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let in_flight = Arc::clone(
+            self.in_flight
+                .lock()
+                .unwrap()
+                .entry(key.to_vec())
+                .or_default(),
+        );
+
+        let mut ran = false;
+
+        let result = in_flight
+            .get_or_try_init(|| {
+                ran = true;
+                async { run().await.map(Arc::new) }
+            })
+            .await
+            .map(Arc::clone);
+
+        // Later calls start a new run.
+        let mut in_flight_map = self.in_flight.lock().unwrap();
+        if in_flight_map
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, &in_flight))
+        {
+            in_flight_map.remove(key);
+        }
+
+        result.map(|value| (value, !ran))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_calls_join_one_run() {
+        let single_flight = SingleFlight::new();
+        let runs = AtomicUsize::new(0);
+        let key = vec!["a".to_owned()];
+
+        let run = || async {
+            runs.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok::<_, ()>(1)
+        };
+
+        let (leader, joiner) = tokio::join!(single_flight.run(&key, run), async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            single_flight.run(&key, run).await
+        });
+
+        assert_eq!(
+            leader.map(|(value, shared)| (*value, shared)),
+            Ok((1, false))
+        );
+        assert_eq!(
+            joiner.map(|(value, shared)| (*value, shared)),
+            Ok((1, true))
+        );
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+        // A call after the run finished starts a new one.
+        assert_eq!(
+            single_flight.run(&key, run).await.map(|(_, shared)| shared),
+            Ok(false)
+        );
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn waiter_runs_again_when_leader_fails() {
+        let single_flight = SingleFlight::<u32>::new();
+        let key = vec!["a".to_owned()];
+
+        let (leader, waiter) = tokio::join!(
+            single_flight.run(&key, || async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Err("failed")
+            }),
+            async {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                single_flight.run(&key, || async { Ok::<_, &str>(2) }).await
+            }
+        );
+
+        assert!(leader.is_err());
+        assert_eq!(
+            waiter.map(|(value, shared)| (*value, shared)),
+            Ok((2, false))
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    config::StreamFormat,
+    handlers::{FastCGIRequest, HttpResponse, HttpResponseBody},
+};
+
+use super::{
+    redact::Redactor,
+    render,
+    runner::{OutputObserver, OutputStream},
+    RunCommandResponse,
+};
+
+#[derive(thiserror::Error, Debug)]
+#[error("unknown stream '{0}', expected text, event_stream or none")]
+pub struct UnknownStreamFormatError(String);
+
+// The stream query parameter overrides the command's configured stream format.
+pub fn requested_stream_format(
+    request: &FastCGIRequest<'_>,
+    default: Option<StreamFormat>,
+) -> Result<Option<StreamFormat>, UnknownStreamFormatError> {
+    match request.query_param("stream").as_deref() {
+        None => Ok(default),
+        Some("text") => Ok(Some(StreamFormat::Text)),
+        Some("event_stream") => Ok(Some(StreamFormat::EventStream)),
+        Some("none") => Ok(None),
+        Some(value) => Err(UnknownStreamFormatError(value.to_owned())),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StreamSummary<'a> {
+    command_duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    timed_out: bool,
+    cancelled: bool,
+    output_truncated: bool,
+    success: bool,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+}
+
+// Sends a command's output lines to a streamed response as they are read.
+pub struct OutputStreamer {
+    stream_format: StreamFormat,
+    redactor: Arc<Redactor>,
+    sender: UnboundedSender<Vec<u8>>,
+    // Incomplete last line of stdout and stderr.
+    partial_lines: Mutex<[Vec<u8>; 2]>,
+}
+
+impl OutputStreamer {
+    pub fn new(
+        stream_format: StreamFormat,
+        redactor: Arc<Redactor>,
+    ) -> (Self, UnboundedReceiver<Vec<u8>>) {
+        let (sender, receiver) = unbounded_channel();
+
+        (
+            Self {
+                stream_format,
+                redactor,
+                sender,
+                partial_lines: Mutex::new([Vec::new(), Vec::new()]),
+            },
+            receiver,
+        )
+    }
+
+    fn send(&self, chunk: Vec<u8>) {
+        // The client has gone away if the receiver was dropped.
+        let _ = self.sender.send(chunk);
+    }
+
+    fn send_event(&self, event: &str, data: &str) {
+        self.send(format!("event: {}\ndata: {}\n\n", event, data).into_bytes());
+    }
+
+    fn send_line(&self, stream: OutputStream, line: &[u8]) {
+        // Redaction is applied line by line, so patterns can not match across lines.
+        let redacted_line;
+        let line = if self.redactor.is_empty() {
+            line
+        } else {
+            redacted_line = self.redactor.redact(&String::from_utf8_lossy(line)).0;
+            redacted_line.as_bytes()
+        };
+
+        match self.stream_format {
+            StreamFormat::Text => self.send(line.to_vec()),
+            StreamFormat::EventStream => {
+                let line = String::from_utf8_lossy(line);
+                let line = line.trim_end_matches('\n').trim_end_matches('\r');
+                let event = match stream {
+                    OutputStream::Stdout => "stdout",
+                    OutputStream::Stderr => "stderr",
+                };
+                self.send_event(event, line);
+            }
+        }
+    }
+
+    // Sends any incomplete last lines and then the summary, ending the stream.
+    pub fn finish(&self, response: &RunCommandResponse<'_>) {
+        let partial_lines = std::mem::take(&mut *self.partial_lines.lock().unwrap());
+
+        for (stream, partial_line) in [OutputStream::Stdout, OutputStream::Stderr]
+            .into_iter()
+            .zip(partial_lines)
+        {
+            if !partial_line.is_empty() {
+                self.send_line(stream, &partial_line);
+                if let StreamFormat::Text = self.stream_format {
+                    self.send(b"\n".to_vec());
+                }
+            }
+        }
+
+        match self.stream_format {
+            StreamFormat::Text => {
+                let mut summary = format!(
+                    "[{} after {} ms",
+                    render::exit_status_text(response),
+                    response.command_duration_ms
+                );
+                if response.timed_out {
+                    summary.push_str(", timed out");
+                }
+                if response.output_truncated {
+                    summary.push_str(", output truncated");
+                }
+                summary.push_str("]\n");
+                self.send(summary.into_bytes());
+            }
+            StreamFormat::EventStream => {
+                let summary = StreamSummary {
+                    command_duration_ms: response.command_duration_ms,
+                    error: response.error.as_deref(),
+                    timed_out: response.timed_out,
+                    cancelled: response.cancelled,
+                    output_truncated: response.output_truncated,
+                    success: response.success,
+                    exit_code: response.exit_code,
+                    signal: response.signal,
+                };
+                self.send_event(
+                    "summary",
+                    &serde_json::to_string(&summary).unwrap_or_default(),
+                );
+            }
+        }
+    }
+}
+
+impl OutputObserver for OutputStreamer {
+    fn output(&self, stream: OutputStream, data: &[u8]) {
+        let mut partial_lines = self.partial_lines.lock().unwrap();
+
+        let partial_line = match stream {
+            OutputStream::Stdout => &mut partial_lines[0],
+            OutputStream::Stderr => &mut partial_lines[1],
+        };
+
+        partial_line.extend_from_slice(data);
+
+        while let Some(newline_index) = partial_line.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = partial_line.drain(..=newline_index).collect();
+            self.send_line(stream, &line);
+        }
+    }
+}
+
+pub fn build_stream_response(
+    stream_format: StreamFormat,
+    receiver: UnboundedReceiver<Vec<u8>>,
+) -> HttpResponse {
+    let content_type = match stream_format {
+        StreamFormat::Text => "text/plain; charset=utf-8",
+        StreamFormat::EventStream => "text/event-stream",
+    };
+
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        // Asks nginx not to buffer the response.
+        .header("x-accel-buffering", "no")
+        .body(Some(HttpResponseBody::from(receiver)))
+        .unwrap()
+}
@@ -0,0 +1,163 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Context;
+
+use async_trait::async_trait;
+
+use log::debug;
+
+use tokio::time::Instant;
+
+use crate::handlers::{FastCGIRequest, HttpResponse, RequestHandler};
+
+// A middleware wraps a RequestHandler.  It may inspect or modify the request before
+// calling next.run, modify the returned HttpResponse, or short-circuit by returning
+// a response without calling next.run at all.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn handle(&self, request: FastCGIRequest<'_>, next: Next<'_>) -> HttpResponse;
+}
+
+// The remaining middlewares in the chain followed by the wrapped handler.
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn RequestHandler,
+}
+
+impl<'a> Next<'a> {
+    pub async fn run(self, request: FastCGIRequest<'_>) -> HttpResponse {
+        match self.middlewares.split_first() {
+            Some((middleware, remaining_middlewares)) => {
+                let next = Next {
+                    middlewares: remaining_middlewares,
+                    handler: self.handler,
+                };
+                middleware.handle(request, next).await
+            }
+            None => self.handler.handle(request).await,
+        }
+    }
+}
+
+// Middlewares run in the order given: the first middleware is the outermost,
+// it sees the request first and the response last.
+pub struct MiddlewareHandler {
+    middlewares: Vec<Arc<dyn Middleware>>,
+    handler: Box<dyn RequestHandler>,
+}
+
+impl MiddlewareHandler {
+    pub fn new(middlewares: Vec<Arc<dyn Middleware>>, handler: Box<dyn RequestHandler>) -> Self {
+        Self {
+            middlewares,
+            handler,
+        }
+    }
+}
+
+#[async_trait]
+impl RequestHandler for MiddlewareHandler {
+    async fn handle(&self, request: FastCGIRequest<'_>) -> HttpResponse {
+        let next = Next {
+            middlewares: &self.middlewares,
+            handler: self.handler.as_ref(),
+        };
+
+        next.run(request).await
+    }
+}
+
+// Wrap handler with middlewares, returning handler unchanged if there are none.
+pub fn wrap_handler(
+    middlewares: Vec<Arc<dyn Middleware>>,
+    handler: Box<dyn RequestHandler>,
+) -> Box<dyn RequestHandler> {
+    if middlewares.is_empty() {
+        handler
+    } else {
+        Box::new(MiddlewareHandler::new(middlewares, handler))
+    }
+}
+
+pub struct RequestLogMiddleware {}
+
+impl RequestLogMiddleware {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+#[async_trait]
+impl Middleware for RequestLogMiddleware {
+    async fn handle(&self, request: FastCGIRequest<'_>, next: Next<'_>) -> HttpResponse {
+        let connection_id = *request.connection_id();
+        let request_id = *request.request_id();
+        let request_method = request.param("request_method").unwrap_or("[Unknown]");
+        let request_uri = request.request_uri().unwrap_or("[Unknown URI]");
+
+        let start_time = Instant::now();
+        let response = next.run(request).await;
+        let duration = start_time.elapsed();
+
+        debug!(
+            "connection_id {:?} request_id {:?} {} {} status {} duration {:?}",
+            connection_id,
+            request_id,
+            request_method,
+            request_uri,
+            response.status().as_u16(),
+            duration,
+        );
+
+        response
+    }
+}
+
+pub struct ResponseHeadersMiddleware {
+    headers: http::HeaderMap,
+}
+
+impl ResponseHeadersMiddleware {
+    pub fn new(headers: &BTreeMap<String, String>) -> anyhow::Result<Self> {
+        let mut header_map = http::HeaderMap::with_capacity(headers.len());
+
+        for (key, value) in headers {
+            let header_name = http::header::HeaderName::from_bytes(key.as_bytes())
+                .with_context(|| format!("invalid response header name '{}'", key))?;
+
+            let header_value = http::header::HeaderValue::from_str(value)
+                .with_context(|| format!("invalid response header value '{}'", value))?;
+
+            header_map.insert(header_name, header_value);
+        }
+
+        Ok(Self {
+            headers: header_map,
+        })
+    }
+}
+
+#[async_trait]
+impl Middleware for ResponseHeadersMiddleware {
+    async fn handle(&self, request: FastCGIRequest<'_>, next: Next<'_>) -> HttpResponse {
+        let mut response = next.run(request).await;
+
+        let response_headers = response.headers_mut();
+        for (key, value) in self.headers.iter() {
+            response_headers.insert(key, value.clone());
+        }
+
+        response
+    }
+}
+
+// Middlewares for a route configured with response_headers.
+pub fn response_headers_middlewares(
+    headers: &BTreeMap<String, String>,
+) -> anyhow::Result<Vec<Arc<dyn Middleware>>> {
+    if headers.is_empty() {
+        Ok(Vec::new())
+    } else {
+        Ok(vec![Arc::new(ResponseHeadersMiddleware::new(headers)?)])
+    }
+}
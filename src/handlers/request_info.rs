@@ -44,8 +44,7 @@ impl RequestHandler for RequestInfoHandler {
         };
 
         for (key, value) in request.params().iter() {
-            if key.starts_with("http_") {
-                let http_header_key = &key[5..];
+            if let Some(http_header_key) = key.strip_prefix("http_") {
                 response.http_headers.insert(http_header_key, value);
             } else {
                 response.other_params.insert(key, value);
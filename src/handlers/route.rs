@@ -10,14 +10,20 @@ use crate::handlers::{
 
 pub type PathSuffixAndHandler = (PathBuf, Box<dyn RequestHandler>);
 
+// A path suffix ending in WILDCARD_SEGMENT matches any single segment in its place,
+// the handler reads the segment from the request uri.
+pub const WILDCARD_SEGMENT: &str = "*";
+
 pub struct Router {
     uri_to_request_handler: HashMap<String, Box<dyn RequestHandler>>,
+    parent_uri_to_wildcard_handler: HashMap<String, Box<dyn RequestHandler>>,
 }
 
 impl Router {
     pub fn new(routes: Vec<PathSuffixAndHandler>) -> anyhow::Result<Self> {
         let mut router = Self {
             uri_to_request_handler: HashMap::with_capacity(routes.len()),
+            parent_uri_to_wildcard_handler: HashMap::new(),
         };
 
         let context_configuration = crate::config::instance().context_configuration();
@@ -32,8 +38,15 @@ impl Router {
                 )
             })?;
 
-            if router
-                .uri_to_request_handler
+            let (uri_to_request_handler, uri) = match uri.strip_suffix(WILDCARD_SEGMENT) {
+                Some(parent_uri) if parent_uri.ends_with('/') => (
+                    &mut router.parent_uri_to_wildcard_handler,
+                    parent_uri.trim_end_matches('/'),
+                ),
+                _ => (&mut router.uri_to_request_handler, uri),
+            };
+
+            if uri_to_request_handler
                 .insert(uri.to_owned(), handler)
                 .is_some()
             {
@@ -51,7 +64,13 @@ impl RequestHandler for Router {
             None => build_status_code_response(http::StatusCode::BAD_REQUEST),
             Some(request_uri) => match self.uri_to_request_handler.get(*request_uri) {
                 Some(handler) => handler.handle(request).await,
-                None => build_status_code_response(http::StatusCode::NOT_FOUND),
+                None => match request_uri
+                    .rsplit_once('/')
+                    .and_then(|(parent_uri, _)| self.parent_uri_to_wildcard_handler.get(parent_uri))
+                {
+                    Some(handler) => handler.handle(request).await,
+                    None => build_status_code_response(http::StatusCode::NOT_FOUND),
+                },
             },
         }
     }
@@ -2,17 +2,130 @@ use log::warn;
 
 use serde::Serialize;
 
-use crate::response::{body::HttpResponseBody, HttpResponse};
+use crate::{
+    request::FastCGIRequest,
+    response::{body::HttpResponseBody, HttpResponse},
+};
 
-pub fn build_json_body_response(http_response_body: HttpResponseBody) -> HttpResponse {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    Text,
+    Html,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("unknown format '{0}', expected one of json, text, html")]
+pub struct UnknownResponseFormatError(String);
+
+impl ResponseFormat {
+    fn from_format_param(format: &str) -> Option<Self> {
+        match format {
+            "json" => Some(Self::Json),
+            "text" => Some(Self::Text),
+            "html" => Some(Self::Html),
+            _ => None,
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/json" => Some(Self::Json),
+            "text/plain" => Some(Self::Text),
+            "text/html" => Some(Self::Html),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Text => "text/plain; charset=utf-8",
+            Self::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    // A format query parameter takes precedence over the Accept header.
+    // Accept entries are tried in order of decreasing quality, defaulting to json.
+    pub fn negotiate(request: &FastCGIRequest<'_>) -> Result<Self, UnknownResponseFormatError> {
+        if let Some(format) = request.query_param("format") {
+            return Self::from_format_param(&format).ok_or(UnknownResponseFormatError(format));
+        }
+
+        Ok(request
+            .param("http_accept")
+            .map_or(Self::Json, Self::from_accept))
+    }
+
+    fn from_accept(accept: &str) -> Self {
+        let mut media_ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|media_range| {
+                let mut parts = media_range.split(';').map(str::trim);
+                let media_type = parts.next().unwrap_or_default();
+                let quality = parts
+                    .find_map(|part| part.strip_prefix("q="))
+                    .and_then(|quality| quality.parse().ok())
+                    .unwrap_or(1.0);
+                (media_type, quality)
+            })
+            .collect();
+
+        media_ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        media_ranges
+            .into_iter()
+            .filter(|(_, quality)| *quality > 0.0)
+            .find_map(|(media_type, _)| Self::from_media_type(media_type))
+            .unwrap_or(Self::Json)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse<'a> {
+    error: &'a str,
+}
+
+pub fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+pub fn build_body_response(
+    response_format: ResponseFormat,
+    http_response_body: HttpResponseBody,
+) -> HttpResponse {
     http::Response::builder()
         .status(http::StatusCode::OK)
-        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(http::header::CONTENT_TYPE, response_format.content_type())
         .body(Some(http_response_body))
         .unwrap()
 }
 
+pub fn build_json_body_response(http_response_body: HttpResponseBody) -> HttpResponse {
+    build_body_response(ResponseFormat::Json, http_response_body)
+}
+
 pub fn build_json_response(response_dto: impl Serialize) -> HttpResponse {
+    build_json_status_response(http::StatusCode::OK, response_dto)
+}
+
+pub fn build_json_status_response(
+    status_code: http::StatusCode,
+    response_dto: impl Serialize,
+) -> HttpResponse {
     let json_result = serde_json::to_string(&response_dto);
 
     match json_result {
@@ -24,13 +137,84 @@ pub fn build_json_response(response_dto: impl Serialize) -> HttpResponse {
                 .body(None)
                 .unwrap()
         }
-        Ok(json_string) => build_json_body_response(HttpResponseBody::from(json_string)),
+        Ok(json_string) => {
+            let mut response = build_json_body_response(HttpResponseBody::from(json_string));
+            *response.status_mut() = status_code;
+            response
+        }
     }
 }
 
+pub fn build_json_error_response(status_code: http::StatusCode, error: &str) -> HttpResponse {
+    build_json_status_response(status_code, ErrorResponse { error })
+}
+
 pub fn build_status_code_response(status_code: http::StatusCode) -> HttpResponse {
     http::Response::builder()
         .status(status_code)
         .body(None)
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_prefers_highest_quality() {
+        assert_eq!(
+            ResponseFormat::from_accept("text/plain;q=0.5, text/html"),
+            ResponseFormat::Html
+        );
+        assert_eq!(
+            ResponseFormat::from_accept("text/html; q=0.2, application/json; q=0.9, text/plain"),
+            ResponseFormat::Text
+        );
+    }
+
+    #[test]
+    fn accept_keeps_order_for_equal_quality() {
+        assert_eq!(
+            ResponseFormat::from_accept("text/plain, text/html"),
+            ResponseFormat::Text
+        );
+        assert_eq!(
+            ResponseFormat::from_accept("text/html;q=0.8,text/plain;q=0.8"),
+            ResponseFormat::Html
+        );
+    }
+
+    #[test]
+    fn accept_skips_unknown_and_refused_types() {
+        assert_eq!(
+            ResponseFormat::from_accept("image/png, text/plain;q=0.1"),
+            ResponseFormat::Text
+        );
+        assert_eq!(
+            ResponseFormat::from_accept("text/html;q=0, */*"),
+            ResponseFormat::Json
+        );
+        assert_eq!(
+            ResponseFormat::from_accept("text/html;q=bad"),
+            ResponseFormat::Html
+        );
+        assert_eq!(ResponseFormat::from_accept(""), ResponseFormat::Json);
+    }
+
+    #[test]
+    fn format_param_values() {
+        assert_eq!(
+            ResponseFormat::from_format_param("text"),
+            Some(ResponseFormat::Text)
+        );
+        assert_eq!(ResponseFormat::from_format_param("xml"), None);
+    }
+
+    #[test]
+    fn html_escape_special_characters() {
+        assert_eq!(
+            html_escape("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+}
@@ -15,7 +15,7 @@ mod server;
 mod utils;
 
 fn app_name() -> String {
-    std::env::args().nth(0).unwrap_or("[UNKNOWN]".to_owned())
+    std::env::args().next().unwrap_or("[UNKNOWN]".to_owned())
 }
 
 async fn run_server(server: server::Server) -> anyhow::Result<()> {
@@ -1,5 +1,11 @@
+use std::io::Read;
+
 use getset::Getters;
 
+use log::warn;
+
+use tokio_util::sync::CancellationToken;
+
 use crate::{connection::FastCGIConnectionID, utils::GenericAsyncWriter};
 
 #[derive(Clone, Copy, Debug)]
@@ -15,12 +21,16 @@ pub struct FastCGIRequest<'a> {
     request_id: FastCGIRequestID,
     request_uri: Option<&'a str>,
     params: Vec<ParamKeyValue<'a>>,
+    body: Vec<u8>,
+    // Cancelled when the web server aborts the request or closes the connection.
+    cancellation_token: CancellationToken,
 }
 
 impl<'a> FastCGIRequest<'a> {
     pub fn new(
         connection_id: FastCGIConnectionID,
         request: &'a tokio_fastcgi::Request<impl GenericAsyncWriter>,
+        cancellation_token: CancellationToken,
     ) -> FastCGIRequest<'a> {
         Self {
             role: match request.role {
@@ -30,7 +40,12 @@ impl<'a> FastCGIRequest<'a> {
             },
             connection_id,
             request_id: FastCGIRequestID(request.get_request_id()),
-            request_uri: request.get_str_param("request_uri"),
+            // Web servers include the query string, which is read from query_string instead.
+            request_uri: request.get_str_param("request_uri").map(|request_uri| {
+                request_uri
+                    .split_once('?')
+                    .map_or(request_uri, |(path, _)| path)
+            }),
             params: match request.str_params_iter() {
                 Some(iter) => iter
                     .filter(|v| v.0 != "request_uri")
@@ -38,6 +53,49 @@ impl<'a> FastCGIRequest<'a> {
                     .collect(),
                 None => Vec::new(),
             },
+            body: Self::read_body(request),
+            cancellation_token,
+        }
+    }
+
+    fn read_body(request: &tokio_fastcgi::Request<impl GenericAsyncWriter>) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        if let Err(err) = request.get_stdin().read_to_end(&mut body) {
+            warn!("error reading request body: {}", err);
         }
+
+        body
+    }
+
+    pub fn param(&self, key: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(param_key, _)| *param_key == key)
+            .map(|(_, value)| *value)
+    }
+
+    pub fn query_param(&self, key: &str) -> Option<String> {
+        let query_string = self.param("query_string")?;
+
+        form_urlencoded::parse(query_string.as_bytes())
+            .find(|(param_key, _)| param_key == key)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    // Looks up key in the query string, then in an application/x-www-form-urlencoded body.
+    pub fn query_or_form_param(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.query_param(key) {
+            return Some(value);
+        }
+
+        let content_type = self.param("content_type")?;
+        if !content_type.starts_with("application/x-www-form-urlencoded") {
+            return None;
+        }
+
+        form_urlencoded::parse(&self.body)
+            .find(|(param_key, _)| param_key == key)
+            .map(|(_, value)| value.into_owned())
     }
 }
@@ -1,17 +1,24 @@
 use std::sync::Arc;
 
+use tokio::sync::mpsc::UnboundedReceiver;
+
 #[derive(Debug)]
 pub enum HttpResponseBody {
     ArcString(Arc<String>),
 
     String(String),
+
+    // Chunks are written as they are received until the sender is dropped.
+    Stream(UnboundedReceiver<Vec<u8>>),
 }
 
 impl HttpResponseBody {
-    pub(super) fn as_bytes(&self) -> &[u8] {
+    // None for a streamed body.
+    pub(super) fn as_bytes(&self) -> Option<&[u8]> {
         match self {
-            Self::ArcString(a) => a.as_bytes(),
-            Self::String(s) => s.as_bytes(),
+            Self::ArcString(a) => Some(a.as_bytes()),
+            Self::String(s) => Some(s.as_bytes()),
+            Self::Stream(_) => None,
         }
     }
 }
@@ -27,3 +34,9 @@ impl From<String> for HttpResponseBody {
         Self::String(s)
     }
 }
+
+impl From<UnboundedReceiver<Vec<u8>>> for HttpResponseBody {
+    fn from(receiver: UnboundedReceiver<Vec<u8>>) -> Self {
+        Self::Stream(receiver)
+    }
+}
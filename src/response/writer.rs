@@ -4,8 +4,12 @@ use log::{debug, warn};
 
 use tokio_fastcgi::{Request, RequestResult};
 
-use crate::{response::HttpResponse, utils::GenericAsyncWriter};
+use crate::{
+    response::{body::HttpResponseBody, HttpResponse},
+    utils::GenericAsyncWriter,
+};
 
+#[allow(clippy::enum_variant_names)]
 #[derive(thiserror::Error, Debug)]
 enum SendResponseError {
     #[error("build header string error: {0}")]
@@ -13,6 +17,9 @@ enum SendResponseError {
 
     #[error("tokio_fastcgi write error: {0}")]
     TokioFastCGIWriteError(#[from] tokio_fastcgi::Error),
+
+    #[error("flush error: {0}")]
+    FlushError(#[from] std::io::Error),
 }
 
 pub struct ResponseWriter<W: GenericAsyncWriter> {
@@ -28,9 +35,9 @@ impl<W: GenericAsyncWriter> ResponseWriter<W> {
     fn build_header_string(&self) -> Result<String, std::fmt::Error> {
         let mut header_string = String::new();
 
-        write!(
+        writeln!(
             header_string,
-            "Status: {} {}\n",
+            "Status: {} {}",
             self.response.status().as_u16(),
             self.response
                 .status()
@@ -39,9 +46,9 @@ impl<W: GenericAsyncWriter> ResponseWriter<W> {
         )?;
 
         for (key, value) in self.response.headers() {
-            write!(
+            writeln!(
                 header_string,
-                "{}: {}\n",
+                "{}: {}",
                 key.as_str(),
                 value.to_str().unwrap_or("[Unknown]")
             )?;
@@ -59,8 +66,19 @@ impl<W: GenericAsyncWriter> ResponseWriter<W> {
 
         stdout.write(&header_string.into_bytes()).await?;
 
-        if let Some(http_response_body) = self.response.into_body() {
-            stdout.write(http_response_body.as_bytes()).await?;
+        match self.response.into_body() {
+            None => {}
+            Some(HttpResponseBody::Stream(mut receiver)) => {
+                while let Some(chunk) = receiver.recv().await {
+                    stdout.write(&chunk).await?;
+                    stdout.flush().await?;
+                }
+            }
+            Some(http_response_body) => {
+                if let Some(bytes) = http_response_body.as_bytes() {
+                    stdout.write(bytes).await?;
+                }
+            }
         }
 
         Ok(())
@@ -1,3 +1,4 @@
+mod abort;
 mod processor;
 mod tcp;
 mod unix;
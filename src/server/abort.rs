@@ -0,0 +1,321 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use log::{debug, info};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{connection::FastCGIConnectionID, utils::GenericAsyncReader};
+
+const FASTCGI_HEADER_LEN: usize = 8;
+
+const FASTCGI_BEGIN_REQUEST: u8 = 1;
+
+const FASTCGI_ABORT_REQUEST: u8 = 2;
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Default)]
+struct RequestTokens {
+    tokens: HashMap<u16, CancellationToken>,
+    // Set when the connection closed, requests not yet started are cancelled from the start.
+    closed: bool,
+}
+
+// Cancellation tokens for the requests in flight on one connection.  A request's token is
+// cancelled when the web server aborts it or closes the connection.
+//
+// A request that tokio-fastcgi rejects, for example over max_concurrent_connections, is never
+// processed, so its token is only removed when its id is reused or the connection closes.
+// There is at most one token per request id.
+pub struct RequestCancellations {
+    connection_id: FastCGIConnectionID,
+    request_tokens: Mutex<RequestTokens>,
+}
+
+impl RequestCancellations {
+    pub fn new(connection_id: FastCGIConnectionID) -> Self {
+        Self {
+            connection_id,
+            request_tokens: Mutex::new(RequestTokens::default()),
+        }
+    }
+
+    fn begin(&self, request_id: u16) {
+        self.request_tokens
+            .lock()
+            .unwrap()
+            .tokens
+            .insert(request_id, CancellationToken::new());
+    }
+
+    // The token stays registered until finish, so a handler that has not started yet
+    // still sees the abort.
+    fn abort(&self, request_id: u16) {
+        if let Some(token) = self.request_tokens.lock().unwrap().tokens.get(&request_id) {
+            info!(
+                "connection_id {:?} request_id {} aborted by web server",
+                self.connection_id, request_id
+            );
+            token.cancel();
+        }
+    }
+
+    fn close(&self) {
+        let mut request_tokens = self.request_tokens.lock().unwrap();
+
+        request_tokens.closed = true;
+
+        for (request_id, token) in request_tokens.tokens.drain() {
+            info!(
+                "connection_id {:?} request_id {} aborted, connection closed",
+                self.connection_id, request_id
+            );
+            token.cancel();
+        }
+    }
+
+    pub fn token(&self, request_id: u16) -> CancellationToken {
+        let mut request_tokens = self.request_tokens.lock().unwrap();
+
+        if request_tokens.closed {
+            let token = CancellationToken::new();
+            token.cancel();
+            return token;
+        }
+
+        request_tokens.tokens.entry(request_id).or_default().clone()
+    }
+
+    // Called before the request's end record is written, so the web server can not have
+    // reused the request id yet.
+    pub fn finish(&self, request_id: u16) {
+        self.request_tokens
+            .lock()
+            .unwrap()
+            .tokens
+            .remove(&request_id);
+    }
+}
+
+// Follows the record headers in the bytes read from the web server.  Abort records are
+// consumed here, tokio-fastcgi would otherwise end the request a second time.
+#[derive(Default)]
+struct RecordHeaderParser {
+    header: [u8; FASTCGI_HEADER_LEN],
+    header_len: usize,
+    // Content and padding bytes left in the current record.
+    skip_len: usize,
+    // True when the current record is not forwarded.
+    dropping: bool,
+}
+
+impl RecordHeaderParser {
+    // Appends the bytes to forward to the request parser to forward_buffer.
+    fn parse(
+        &mut self,
+        mut data: &[u8],
+        request_cancellations: &RequestCancellations,
+        forward_buffer: &mut Vec<u8>,
+    ) {
+        while !data.is_empty() {
+            if self.skip_len > 0 {
+                let skip_len = self.skip_len.min(data.len());
+                if !self.dropping {
+                    forward_buffer.extend_from_slice(&data[..skip_len]);
+                }
+                self.skip_len -= skip_len;
+                data = &data[skip_len..];
+                continue;
+            }
+
+            let copy_len = (FASTCGI_HEADER_LEN - self.header_len).min(data.len());
+            self.header[self.header_len..self.header_len + copy_len]
+                .copy_from_slice(&data[..copy_len]);
+            self.header_len += copy_len;
+            data = &data[copy_len..];
+
+            if self.header_len == FASTCGI_HEADER_LEN {
+                self.header_len = 0;
+
+                let record_type = self.header[1];
+                let request_id = u16::from_be_bytes([self.header[2], self.header[3]]);
+                let content_len = u16::from_be_bytes([self.header[4], self.header[5]]);
+                let padding_len = self.header[6];
+
+                self.skip_len = usize::from(content_len) + usize::from(padding_len);
+
+                self.dropping = record_type == FASTCGI_ABORT_REQUEST;
+
+                match record_type {
+                    FASTCGI_BEGIN_REQUEST => request_cancellations.begin(request_id),
+                    FASTCGI_ABORT_REQUEST => request_cancellations.abort(request_id),
+                    _ => {}
+                }
+
+                if !self.dropping {
+                    forward_buffer.extend_from_slice(&self.header);
+                }
+            }
+        }
+    }
+}
+
+// Copies the web server's bytes to the request parser while watching for aborted requests.
+// Keeps reading after the parser has stopped so a closed connection is still noticed.
+pub async fn forward_and_watch(
+    mut reader: impl GenericAsyncReader,
+    mut request_parser_writer: DuplexStream,
+    request_cancellations: &RequestCancellations,
+) {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    let mut forward_buffer = Vec::with_capacity(READ_BUFFER_SIZE);
+    let mut record_header_parser = RecordHeaderParser::default();
+    let mut forwarding = true;
+
+    loop {
+        let bytes_read = match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(err) => {
+                debug!(
+                    "connection_id {:?} read error: {}",
+                    request_cancellations.connection_id, err
+                );
+                break;
+            }
+        };
+
+        // Register new requests before the parser can hand them to a handler.
+        forward_buffer.clear();
+        record_header_parser.parse(
+            &buffer[..bytes_read],
+            request_cancellations,
+            &mut forward_buffer,
+        );
+
+        if forwarding
+            && request_parser_writer
+                .write_all(&forward_buffer)
+                .await
+                .is_err()
+        {
+            forwarding = false;
+        }
+    }
+
+    request_cancellations.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::ServerType, connection::FastCGIConnectionIDFactory};
+
+    use super::*;
+
+    const FASTCGI_PARAMS: u8 = 4;
+    const FASTCGI_STDIN: u8 = 5;
+
+    fn record(record_type: u8, request_id: u16, content: &[u8], padding_len: u8) -> Vec<u8> {
+        let mut record = vec![1, record_type];
+        record.extend_from_slice(&request_id.to_be_bytes());
+        record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        record.extend_from_slice(&[padding_len, 0]);
+        record.extend_from_slice(content);
+        record.extend(std::iter::repeat_n(0, padding_len.into()));
+        record
+    }
+
+    fn request_cancellations() -> RequestCancellations {
+        RequestCancellations::new(
+            FastCGIConnectionIDFactory::new(ServerType::UNIX).new_connection_id(),
+        )
+    }
+
+    // Feeds data to a new parser in chunks of chunk_len, returning the forwarded bytes.
+    fn parse_in_chunks(
+        data: &[u8],
+        chunk_len: usize,
+        request_cancellations: &RequestCancellations,
+    ) -> Vec<u8> {
+        let mut record_header_parser = RecordHeaderParser::default();
+        let mut forwarded = Vec::new();
+        for chunk in data.chunks(chunk_len) {
+            record_header_parser.parse(chunk, request_cancellations, &mut forwarded);
+        }
+        forwarded
+    }
+
+    #[test]
+    fn abort_records_are_consumed_at_any_buffer_boundary() {
+        let begin_1 = record(FASTCGI_BEGIN_REQUEST, 1, &[0, 1, 1, 0, 0, 0, 0, 0], 0);
+        let begin_2 = record(FASTCGI_BEGIN_REQUEST, 2, &[0, 1, 1, 0, 0, 0, 0, 0], 0);
+        let params_1 = record(FASTCGI_PARAMS, 1, b"\x03\x01abcd", 3);
+        let abort_2 = record(FASTCGI_ABORT_REQUEST, 2, b"", 5);
+        let stdin_1 = record(FASTCGI_STDIN, 1, &[FASTCGI_ABORT_REQUEST; 20], 4);
+
+        let data = [
+            begin_1.clone(),
+            begin_2.clone(),
+            params_1.clone(),
+            abort_2,
+            stdin_1.clone(),
+        ]
+        .concat();
+        let expected = [begin_1, begin_2, params_1, stdin_1].concat();
+
+        for chunk_len in 1..=data.len() {
+            let request_cancellations = request_cancellations();
+
+            assert_eq!(
+                parse_in_chunks(&data, chunk_len, &request_cancellations),
+                expected,
+                "chunk_len {}",
+                chunk_len
+            );
+            assert!(!request_cancellations.token(1).is_cancelled());
+            assert!(request_cancellations.token(2).is_cancelled());
+        }
+    }
+
+    #[test]
+    fn abort_before_handler_starts_is_seen() {
+        let request_cancellations = request_cancellations();
+
+        let data = [
+            record(FASTCGI_BEGIN_REQUEST, 7, &[0, 1, 0, 0, 0, 0, 0, 0], 0),
+            record(FASTCGI_ABORT_REQUEST, 7, b"", 0),
+        ]
+        .concat();
+
+        parse_in_chunks(&data, data.len(), &request_cancellations);
+
+        // The handler asks for its token only after the abort was read.
+        assert!(request_cancellations.token(7).is_cancelled());
+
+        // A later request reusing the id gets a new token.
+        request_cancellations.finish(7);
+        parse_in_chunks(
+            &record(FASTCGI_BEGIN_REQUEST, 7, &[0, 1, 0, 0, 0, 0, 0, 0], 0),
+            3,
+            &request_cancellations,
+        );
+        assert!(!request_cancellations.token(7).is_cancelled());
+    }
+
+    #[test]
+    fn close_cancels_all_requests() {
+        let request_cancellations = request_cancellations();
+
+        let token_1 = request_cancellations.token(1);
+        let token_2 = request_cancellations.token(2);
+
+        request_cancellations.close();
+
+        assert!(token_1.is_cancelled());
+        assert!(token_2.is_cancelled());
+        // A request parsed before the close whose handler starts later is cancelled too.
+        assert!(request_cancellations.token(3).is_cancelled());
+    }
+}
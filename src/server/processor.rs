@@ -2,15 +2,21 @@ use std::sync::Arc;
 
 use log::warn;
 
-use tokio_fastcgi::{Request, Requests};
+use tokio_fastcgi::{Request, RequestResult, Requests};
 
 use crate::utils::{GenericAsyncReader, GenericAsyncWriter};
 
 use crate::{
-    connection::FastCGIConnectionID, handlers::RequestHandler, request::FastCGIRequest,
+    connection::FastCGIConnectionID,
+    handlers::RequestHandler,
+    request::FastCGIRequest,
     response::writer::ResponseWriter,
+    server::abort::{self, RequestCancellations},
 };
 
+// Buffer between the socket reader and the request parser.
+const REQUEST_PARSER_BUFFER_SIZE: usize = 64 * 1024;
+
 pub struct ConnectionProcessor {
     handlers: Box<dyn RequestHandler>,
     fastcgi_connection_configuration: &'static crate::config::FastCGIConnectionConfiguration,
@@ -29,15 +35,32 @@ impl ConnectionProcessor {
     async fn process_one_request(
         self: Arc<Self>,
         connection_id: FastCGIConnectionID,
+        request_cancellations: Arc<RequestCancellations>,
         request: Request<impl GenericAsyncWriter>,
     ) {
         if let Err(err) = request
             .process(|request| async move {
-                let fastcgi_request = FastCGIRequest::new(connection_id, request.as_ref());
+                let request_id = request.get_request_id();
+
+                let cancellation_token = request_cancellations.token(request_id);
+
+                let fastcgi_request = FastCGIRequest::new(
+                    connection_id,
+                    request.as_ref(),
+                    cancellation_token.clone(),
+                );
 
-                let http_response = self.handlers.handle(fastcgi_request).await;
+                // An aborted request's handler is dropped, killing any command it is running.
+                let request_result = tokio::select! {
+                    http_response = self.handlers.handle(fastcgi_request) => {
+                        ResponseWriter::new(request, http_response).respond().await
+                    }
+                    _ = cancellation_token.cancelled() => RequestResult::Complete(1),
+                };
 
-                ResponseWriter::new(request, http_response).respond().await
+                request_cancellations.finish(request_id);
+
+                request_result
             })
             .await
         {
@@ -57,10 +80,29 @@ impl ConnectionProcessor {
         // If the socket connection was established successfully spawn a new task to handle
         // the requests that the webserver will send us.
         tokio::spawn(async move {
+            let (reader, writer) = split_socket;
+
+            let request_cancellations = Arc::new(RequestCancellations::new(connection_id));
+
+            let (request_parser_reader, request_parser_writer) =
+                tokio::io::duplex(REQUEST_PARSER_BUFFER_SIZE);
+
+            let watcher_request_cancellations = Arc::clone(&request_cancellations);
+
+            tokio::spawn(async move {
+                abort::forward_and_watch(
+                    reader,
+                    request_parser_writer,
+                    &watcher_request_cancellations,
+                )
+                .await
+            });
+
             // Create a new requests handler it will collect the requests from the server and
             // supply a streaming interface.
-            let mut requests = Requests::from_split_socket(
-                split_socket,
+            let mut requests = Requests::new(
+                request_parser_reader,
+                writer,
                 *self
                     .fastcgi_connection_configuration
                     .max_concurrent_connections(),
@@ -72,7 +114,11 @@ impl ConnectionProcessor {
             // Loop over the requests via the next method and process them.
             // Spawn a new task to process each request.
             while let Ok(Some(request)) = requests.next().await {
-                tokio::spawn(Arc::clone(&self).process_one_request(connection_id, request));
+                tokio::spawn(Arc::clone(&self).process_one_request(
+                    connection_id,
+                    Arc::clone(&request_cancellations),
+                    request,
+                ));
             }
         });
     }